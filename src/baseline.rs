@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use globset::GlobSet;
+
+use crate::events::Event;
+use crate::policy::Policy;
+use crate::scanner::build_globs;
+use crate::sha_state::{ShaSet, ShaState};
+
+/// event names `accept` can be narrowed to
+pub const ACCEPTABLE: &[&str] = &["added", "content_changed", "metadata_changed", "perms_changed", "links_changed",
+    "type_changed", "target_changed", "deleted", "moved"];
+
+///
+/// How the observed state differs from the approved baseline, as the events a scan would give
+///
+/// Same rules as a scan with no policy: an entry gone from the baseline whose content turns up
+/// at a new path is a move, and empty files are never matched up as moves.
+pub fn differences(baseline: &ShaSet, observed: &ShaSet) -> Vec<Event> {
+    let mut evs = vec![];
+    let mut added = vec![];
+    let policy = Policy::default();
+    // an unreadable file only gets an entry when there was none, it is nothing to approve
+    for new in observed.iter().filter(|e| !e.is_unreadable()) {
+        match baseline.get(new.path()) {
+            None => added.push(new),
+            Some(old) => evs.extend(policy.changes(old, new)),
+        }
+    }
+    let mut deleted: HashMap<_, Vec<&ShaState>> = HashMap::new();
+    for old in baseline.iter().filter(|e| observed.get(e.path()).is_none()) {
+        deleted.entry(old.sha().clone()).or_default().push(old);
+    }
+    for new in added {
+        let from = match new.size() {
+            Some(0) => None,
+            _ => deleted.get_mut(new.sha()).and_then(|v| v.pop()),
+        };
+        evs.push(match from {
+            Some(old) => Event::Moved { from: old.path().to_path_buf(), to: new.path().to_path_buf(), sha: new.sha().clone() },
+            None => Event::Added { path: new.path().to_path_buf(), sha: new.sha().clone(), size: new.size().unwrap_or(0) },
+        });
+    }
+    let mut left: Vec<&ShaState> = deleted.into_values().flatten().collect();
+    left.sort();
+    evs.extend(left.into_iter().map(|old| Event::Deleted { path: old.path().to_path_buf(), sha: old.sha().clone() }));
+    evs
+}
+
+/// which differences to take into the baseline, an empty list meaning all of them
+#[derive(Default)]
+pub struct Selection {
+    pub paths: Option<GlobSet>,
+    pub events: Vec<String>,
+}
+
+impl Selection {
+    /// the differences at paths matching any of `paths` and of any of the `events` kinds
+    pub fn new(paths: &[String], events: Vec<String>) -> Result<Self> {
+        let paths = if paths.is_empty() { None } else { Some(build_globs(paths)?) };
+        Ok(Selection { paths, events })
+    }
+
+    pub fn matches(&self, ev: &Event) -> bool {
+        let path_ok = match (&self.paths, ev) {
+            (None, _) => true,
+            (Some(g), Event::Moved { from, to, .. }) => g.is_match(from) || g.is_match(to),
+            (Some(g), ev) => g.is_match(ev.path()),
+        };
+        path_ok && (self.events.is_empty() || self.events.iter().any(|e| e == ev.name()))
+    }
+}
+
+/// take the selected differences from `observed` into `baseline` and return them
+pub fn accept(baseline: &mut ShaSet, observed: &ShaSet, selection: &Selection) -> Vec<Event> {
+    let taken: Vec<Event> = differences(baseline, observed).into_iter().filter(|ev| selection.matches(ev)).collect();
+    for ev in &taken {
+        if let Event::Moved { from, .. } | Event::Deleted { path: from, .. } = ev {
+            baseline.remove(from);
+        }
+        if let Some(new) = observed.get(ev.path()).filter(|_| !matches!(ev, Event::Deleted { .. })) {
+            let _ = baseline.add_with_prior(new.clone());
+        }
+    }
+    if !taken.is_empty() {
+        for (root, when) in observed.roots() {
+            baseline.scanned(root, when);
+        }
+    }
+    taken
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::sha_state::testing::{entry, set, EMPTY, SHA_A, SHA_B, SHA_C};
+
+    fn names(evs: &[Event]) -> Vec<String> {
+        let mut names: Vec<String> = evs.iter().map(|ev| match ev {
+            Event::Moved { from, to, .. } => format!("moved {} {}", from.display(), to.display()),
+            ev => format!("{} {}", ev.name(), ev.path().display()),
+        }).collect();
+        names.sort();
+        names
+    }
+
+    fn baseline() -> ShaSet {
+        set([entry("/etc/a", SHA_A, 10), entry("/etc/b", SHA_B, 20), entry("/etc/c", SHA_C, 30), entry("/etc/empty", EMPTY, 0)])
+    }
+
+    fn observed() -> ShaSet {
+        set([entry("/etc/a", SHA_C, 10), entry("/etc/b2", SHA_B, 20), entry("/etc/d", SHA_A, 40), entry("/etc/empty2", EMPTY, 0)])
+    }
+
+    #[test]
+    fn differences_are_scan_events() {
+        assert_eq!(names(&differences(&baseline(), &observed())),
+                   ["added /etc/d", "added /etc/empty2", "content_changed /etc/a", "deleted /etc/c", "deleted /etc/empty",
+                    "moved /etc/b /etc/b2"]);
+        assert!(differences(&observed(), &observed()).is_empty());
+    }
+
+    #[test]
+    fn accept_takes_only_the_selection() {
+        let mut base = baseline();
+        let selection = Selection::new(&["/etc/b*".to_string()], vec![]).unwrap();
+        assert_eq!(names(&accept(&mut base, &observed(), &selection)), ["moved /etc/b /etc/b2"]);
+        assert!(base.get(Path::new("/etc/b")).is_none());
+        assert_eq!(base.get(Path::new("/etc/b2")).unwrap().sha().to_string(), SHA_B);
+
+        let selection = Selection::new(&[], vec!["deleted".to_string()]).unwrap();
+        assert_eq!(names(&accept(&mut base, &observed(), &selection)), ["deleted /etc/c", "deleted /etc/empty"]);
+        assert_eq!(names(&differences(&base, &observed())), ["added /etc/d", "added /etc/empty2", "content_changed /etc/a"]);
+    }
+
+    #[test]
+    fn accepting_everything_leaves_no_differences() {
+        let mut base = baseline();
+        assert_eq!(accept(&mut base, &observed(), &Selection::default()).len(), 6);
+        assert!(differences(&base, &observed()).is_empty());
+        assert_eq!(base.get(Path::new("/etc/a")).unwrap().sha().to_string(), SHA_C);
+    }
+}
@@ -1,9 +1,19 @@
 use anyhow::{anyhow, Context};
 use structopt::StructOpt;
-use std::path::PathBuf;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 use lazy_static::lazy_static;
 use structopt::clap::AppSettings::*;
+use shafiles::read_order::ReadOrder;
+use shafiles::device_pool::DeviceThreads;
+use shafiles::hasher::HashAlgo;
+use shafiles::mem_budget::ByteSize;
+use shafiles::progress::ProgressMode;
+use shafiles::sinks::SinkSpec;
+use shafiles::config::{Config, Profile};
+use shafiles::baseline::ACCEPTABLE;
+use shafiles::dupes::Format;
 
 lazy_static!{
     pub static ref BUILD_INFO: String  = format!("ver: {}  rev: {}  date: {}", env!("CARGO_PKG_VERSION"), env!("VERGEN_SHA_SHORT"), env!("VERGEN_BUILD_DATE"));
@@ -24,55 +34,668 @@ type Result<T> = std::result::Result<T, anyhow::Error>;
 version = BUILD_INFO.as_str(), rename_all = "kebab-case",
 global_settings(& [
     ColoredHelp,
+    ]),
+settings(& [
     ArgRequiredElseHelp
     ]),
 )]
 ///
 /// Scans files for changes and log change types against prior runs
 ///
-/// The state (if kept around) will be used as a reference to detect how files changed. Either
-/// the content (sha1 hash is tracked) or file last modification timestamp will be detected.
-/// If no changes to a particular file are found, then nothing is written.
-/// If a file changes (content or timestamp), then it is logged and updated in the state file, but
-/// also a count is kept for each file as to changes seen.
-///
-/// Of course, the first run will log not changes.  It is later runs using an existing state file will
-/// that do that.
-/// Deleted files will not log a warning... yet.
+/// Options without a command are scan's, as in `shafiles -t /etc -p etc.json`.
 pub struct Cli {
+    #[structopt(short = "v", parse(from_occurrences), global = true)]
+    /// log level - e.g. -vvv is the same as debug while -vv is info level
+    ///
+    /// To true debug your settings you might try trace level or -vvvv
+    pub verbosity: usize,
+
+    #[structopt(short="c", long, global = true, env = "SHAFILES_CONFIG", default_value="/etc/shafiles.toml")]
+    /// TOML config file holding profiles and daemon settings
+    ///
+    /// Only read when a --profile is given or for the daemon.
+    pub config: PathBuf,
+
+    #[structopt(subcommand)]
+    pub cmd: Command,
+}
+
+#[derive(StructOpt, Debug, Clone)]
+#[structopt(rename_all = "kebab-case")]
+pub enum Command {
+    /// Scan the tree once and log changes against prior runs
+    ///
+    /// The state (if kept around) will be used as a reference to detect how files changed. Either
+    /// the content (sha1 hash is tracked) or file last modification timestamp will be detected.
+    /// If no changes to a particular file are found, then nothing is written.
+    /// If a file changes (content or timestamp), then it is logged and updated in the state file, but
+    /// also a count is kept for each file as to changes seen.
+    ///
+    /// Of course, the first run will log not changes.  It is later runs using an existing state file will
+    /// that do that.
+    /// Deleted files are dropped from the state and logged, or logged as moved if their content
+    /// turns up at a new path.
+    Scan(ScanOpts),
+
+    /// Watch the tree and report changes as they happen
+    ///
+    /// The existing state is the baseline.  Files are rehashed once they settle after being
+    /// written, created or moved, and the state is saved periodically and on exit (SIGINT or
+    /// SIGTERM).  Uses inotify on every directory, or fanotify on the whole mount with --fanotify
+    /// when privileged.  When inotify runs out of watches it falls back to periodic full scans.
+    Watch(WatchOpts),
+
+    /// Run scheduled scans of the config's profiles and serve a control socket
+    ///
+    /// Profiles with "every" set are scanned on that schedule, any profile can be scanned via
+    /// `ctl`.  Scans run one at a time unless [daemon] max_running says otherwise, and a profile
+    /// is never scanned twice at once, so overlapping schedules queue up instead of colliding.
+    /// Stops on SIGINT or SIGTERM once running scans finish.
+    Daemon,
+
+    /// Talk to a running daemon over its control socket, answers are printed as JSON
+    Ctl(CtlOpts),
+
+    /// Re-encrypt a state and its backups with a new key or passphrase
+    ///
+    /// The current secret comes from --state-key or --passphrase-file, or the profile, and the
+    /// new one from --new-state-key or --new-passphrase-file.  Give no current one to encrypt a
+    /// plain state, and neither new one to decrypt.
+    Rekey(RekeyOpts),
+
+    /// Take changes seen by the last scan into the approved baseline
+    ///
+    /// Everything that differs between the state and the --baseline is accepted unless
+    /// narrowed by --path or --event.  Each accepted change is logged.
+    Accept(AcceptOpts),
+
+    /// Report files with the same content, from the digests already in the state
+    ///
+    /// Files are grouped by size and digest, and groups are listed by the bytes their extra
+    /// copies take, most first.  Nothing is read unless --confirm is given, so the report is only
+    /// as current as the last scan.
+    Dupes(DupesOpts),
+}
+
+#[derive(StructOpt, Debug, Clone)]
+#[structopt(rename_all = "kebab-case")]
+pub struct AcceptOpts {
+    #[structopt(flatten)]
+    pub scan: ScanOpts,
+
+    #[structopt(long = "path", number_of_values = 1)]
+    /// Only accept changes to paths matching this glob, may be given more than once
+    ///
+    /// A move is accepted when either its old or new path matches.
+    pub paths: Vec<String>,
+
+    #[structopt(long = "event", number_of_values = 1, possible_values(ACCEPTABLE))]
+    /// Only accept this kind of change, may be given more than once
+    pub events: Vec<String>,
+}
+
+#[derive(StructOpt, Debug, Clone)]
+#[structopt(rename_all = "kebab-case")]
+pub struct RekeyOpts {
+    #[structopt(short="P", long)]
+    /// Take the state and its keys from this profile of the config file
+    pub profile: Option<String>,
+
+    #[structopt(short="p", long)]
+    /// state file path
+    pub state_path: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// file holding the key the state is encrypted with now
+    pub state_key: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// file holding the passphrase the state is encrypted with now
+    pub passphrase_file: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// baseline to rekey along with the state
+    pub baseline: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// file holding the key to encrypt with from now on
+    pub new_state_key: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// file holding the passphrase to encrypt with from now on
+    pub new_passphrase_file: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// Seconds to wait for another run holding the state's lock
+    pub wait_lock: Option<u64>,
+}
+
+impl RekeyOpts {
+    /// the state as it is now, flags taking over from the profile if one is given
+    pub fn resolve(&self, config: &Path) -> Result<Profile> {
+        let flags = Profile {
+            state_path: self.state_path.clone(),
+            state_key: self.state_key.clone(),
+            passphrase_file: self.passphrase_file.clone(),
+            baseline: self.baseline.clone(),
+            wait_lock: self.wait_lock,
+            ..Profile::default()
+        };
+        match &self.profile {
+            None => Ok(flags),
+            Some(name) => Ok(flags.overlay(Config::load(config)?.profile(name)?)),
+        }
+    }
+
+    /// the same profile with the new secret in place of the old
+    pub fn renewed(&self, profile: &Profile) -> Profile {
+        Profile {
+            state_key: self.new_state_key.clone(),
+            passphrase_file: self.new_passphrase_file.clone(),
+            ..profile.clone()
+        }
+    }
+}
+
+#[derive(StructOpt, Debug, Clone)]
+#[structopt(rename_all = "kebab-case")]
+pub struct DupesOpts {
+    #[structopt(short="P", long)]
+    /// Take the state and its keys from this profile of the config file
+    pub profile: Option<String>,
+
+    #[structopt(short="p", long)]
+    /// state file path
+    pub state_path: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// file holding the key the state is encrypted with
+    pub state_key: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// file holding the passphrase the state is encrypted with
+    pub passphrase_file: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// If the state file is corrupt, report from the newest backup that reads fine
+    pub from_backup: bool,
+
+    #[structopt(long, default_value = "text", possible_values(Format::VARIANTS))]
+    /// How to print the groups, csv giving one row per path
+    pub format: Format,
+
+    #[structopt(long, default_value = "1")]
+    /// Leave out files smaller than this, e.g. 4K or 1M
+    pub min_size: ByteSize,
+
+    #[structopt(long)]
+    /// Compare the files of each group byte by byte before reporting them
+    ///
+    /// Files that differ are split into their own groups, hard links of one file count as a
+    /// single copy, and files that are gone or cannot be read are left out.
+    pub confirm: bool,
+}
+
+impl DupesOpts {
+    /// the state to report on, flags taking over from the profile if one is given
+    pub fn resolve(&self, config: &Path) -> Result<Profile> {
+        let flags = Profile {
+            state_path: self.state_path.clone(),
+            state_key: self.state_key.clone(),
+            passphrase_file: self.passphrase_file.clone(),
+            from_backup: if self.from_backup { Some(true) } else { None },
+            ..Profile::default()
+        };
+        match &self.profile {
+            None => Ok(flags),
+            Some(name) => Ok(flags.overlay(Config::load(config)?.profile(name)?)),
+        }
+    }
+}
+
+#[derive(StructOpt, Debug, Clone)]
+#[structopt(rename_all = "kebab-case")]
+pub struct CtlOpts {
+    #[structopt(short="S", long)]
+    /// control socket of the daemon, defaults to the one in the config or /run/shafiles.sock
+    pub socket: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    pub req: CtlCommand,
+}
+
+#[derive(StructOpt, Debug, Clone)]
+#[structopt(rename_all = "kebab-case")]
+pub enum CtlCommand {
+    /// State, schedule and progress of every profile
+    Status,
+    /// Start a scan of a profile now, unless it is already queued or running
+    Scan { profile: String },
+    /// Summary of a profile's last finished scan
+    Summary { profile: String },
+}
+
+#[derive(StructOpt, Debug, Clone)]
+#[structopt(rename_all = "kebab-case")]
+pub struct WatchOpts {
+    #[structopt(flatten)]
+    pub scan: ScanOpts,
+
+    #[structopt(long, default_value="2000")]
+    /// Milliseconds a file must go without events before it is rehashed
+    pub settle_ms: u64,
+
+    #[structopt(long, default_value="60")]
+    /// Seconds between saves of the state when something changed
+    pub save_secs: u64,
+
+    #[structopt(long, default_value="3600")]
+    /// Seconds between full scans in fanotify mode or when watches ran out
+    ///
+    /// fanotify only reports writes, so deletes and moves are caught by these scans.
+    pub rescan_secs: u64,
+
+    #[structopt(long)]
+    /// Do a full scan before starting to watch so the baseline is current
+    pub initial_scan: bool,
+
+    #[structopt(long)]
+    /// Use fanotify on the mount holding the top dir instead of inotify - needs CAP_SYS_ADMIN
+    pub fanotify: bool,
+}
+
+#[derive(StructOpt, Debug, Clone)]
+#[structopt(rename_all = "kebab-case")]
+pub struct ScanOpts {
+
+    #[structopt(short="P", long)]
+    /// Take settings from this profile of the config file, flags given here override it
+    pub profile: Option<String>,
 
     #[structopt(short="t", long)]
-    /// top of the tree to scan
-    pub top_dir: PathBuf,
+    /// top of the tree to scan, or several to walk together into the one state
+    ///
+    /// e.g. -t /etc /usr/local /opt/app.  Deletions are only looked for under the roots given
+    /// and the summary has counts for each root.
+    pub top_dir: Vec<PathBuf>,
 
     #[structopt(short="d", long)]
     /// Number of directory scanning threads
     ///
-    /// These threads find the files to perform sha1 on
-    pub threads_dir: usize,
+    /// These threads find the files to perform sha1 on.  Defaults to 2 when the roots are all
+    /// on spinning disks and the cpu count (2 to 8) otherwise.
+    pub threads_dir: Option<usize>,
 
     #[structopt(short="s", long)]
     /// Number of sha1 threads
     ///
-    /// These threads find the files to perform sha1 on
-    pub threads_sha: usize,
+    /// These threads read the files and perform the sha1.  Defaults to 1 when the roots are all
+    /// on spinning disks and the cpu count otherwise.  With --per-device and no count given, each device picks
+    /// its own.
+    pub threads_sha: Option<usize>,
 
-    #[structopt(short = "v", parse(from_occurrences))]
-    /// log level - e.g. -vvv is the same as debug while -vv is info level
+    #[structopt(long)]
+    /// Vary the number of active sha threads during the run based on measured throughput
     ///
-    /// To true debug your settings you might try trace level or -vvvv
-    pub verbosity: usize,
+    /// Each pool starts twice the sha thread count and moves the number actually reading up
+    /// or down every few seconds.  The best count found is logged at the end of the run.
+    pub auto_tune: bool,
+
+    #[structopt(long, conflicts_with = "auto-tune")]
+    /// Keep the sha thread count fixed even if the profile auto tunes
+    pub no_auto_tune: bool,
 
     #[structopt(short="p", long)]
     /// state file path
-    pub state_path: PathBuf,
+    pub state_path: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// Seconds to wait for another run holding the state's lock, instead of failing at once
+    ///
+    /// The lock is a flock on a .lock file next to the state, held for the whole run, and the
+    /// error names the PID holding it.
+    pub wait_lock: Option<u64>,
+
+    #[structopt(long)]
+    /// Keep this many older state files, as <state>.1 (newest) to <state>.N [default: 0]
+    pub keep_backups: Option<usize>,
+
+    #[structopt(long)]
+    /// If the state file is corrupt, start from the newest backup that reads fine
+    pub from_backup: bool,
+
+    #[structopt(long)]
+    /// File holding a key to HMAC the state with, loading fails if the HMAC does not match
+    pub hmac_key: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// File holding a 32 byte Ed25519 secret key, raw or hex, to sign the state with
+    ///
+    /// Only the run that writes the state needs it, loading checks the signature against
+    /// --verify-key or else the key's public half.  Without any key the state still carries a
+    /// sha256 checksum, which catches damage but not a careful attacker.
+    pub signing_key: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// File holding the 32 byte Ed25519 public key, raw or hex, to check the state's signature with
+    ///
+    /// It is logged when the signing key is loaded.  Loading fails if the signature does not match.
+    pub verify_key: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// Start from an empty state when there is none, even though keys are given
+    ///
+    /// With keys a missing state or baseline is an error, as deleting it would get around the seal.
+    pub init: bool,
+
+    #[structopt(long)]
+    /// Encrypt the state with the 32 byte key in this file, raw or hex
+    ///
+    /// The state lists every path scanned, so encrypt it when that is itself confidential.
+    /// A plain state then fails to load, encrypt it once with rekey.
+    pub state_key: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// Encrypt the state with the passphrase on the first line of this file
+    pub passphrase_file: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// Report changes against this approved baseline instead of the last run
+    ///
+    /// The state is still written every run, but the baseline only changes through `accept`,
+    /// so a change keeps being reported until someone accepts it.  If the baseline does not
+    /// exist yet, it is made from this run's state.
+    pub baseline: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// TOML file of rules saying what to check and how severe a change is, by path glob
+    ///
+    /// e.g. `[[rule]] paths = ["/var/log/**"] check = ["grow_only"] severity = "info"`.
+    /// The first rule matching a path wins, and paths no rule matches are checked for
+    /// everything at info.  A scan with changes rated "alert" exits with status 2.
+    pub policy: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// Exit with status 3 when any path could not be scanned, unless there were alerts
+    ///
+    /// Without it errors are logged, sent as events and counted in the summary by kind, but
+    /// the run still succeeds.
+    pub fail_on_error: bool,
+
+    #[structopt(long, conflicts_with = "fail-on-error")]
+    /// Succeed despite paths that could not be scanned even if the profile fails on them
+    pub no_fail_on_error: bool,
+
+    #[structopt(long)]
+    /// Times to read a file again when its size, mtime or ctime moved while it was read [default: 3]
+    ///
+    /// Retries wait 100ms, doubling each time.  A file still changing after the last one keeps
+    /// its prior entry in the state and is reported as unstable rather than as changed.
+    pub read_retries: Option<u32>,
+
+    #[structopt(long)]
+    /// Leave files changed less than this many seconds ago as they were in the state
+    ///
+    /// Goes by the ctime, so a file written and then touched back to an old mtime still counts
+    /// as changed.  Such files are reported as unstable.
+    pub settle: Option<u64>,
+
+    #[structopt(long, number_of_values = 1)]
+    /// Take the new content of corrupt files matching this glob as good, e.g. after checking it
+    ///
+    /// A file found corrupt keeps its prior hash in the state and is reported on every scan until
+    /// it is restored.  Matching files are reported once more and then keep their new hash.  May
+    /// be given more than once.
+    pub accept_corruption: Vec<String>,
+
+    #[structopt(long, possible_values(HashAlgo::VARIANTS))]
+    /// Content hash to use: sha1, sha256 or blake3 [default: sha1]
+    ///
+    /// Changing it for an existing state shows every file as changed once.
+    pub hash: Option<HashAlgo>,
+
+    #[structopt(long)]
+    /// Hash the files symlinks point to and walk linked directories, instead of recording the links
+    ///
+    /// Each directory is walked once however many links lead to it, which also stops loops.
+    /// Links to nothing are still recorded as links.
+    pub follow_symlinks: bool,
+
+    #[structopt(long, conflicts_with = "follow-symlinks")]
+    /// Record symlinks as links even if the profile follows them
+    pub no_follow_symlinks: bool,
+
+    #[structopt(short="x", long, number_of_values = 1)]
+    /// Skip files and directories whose path matches this glob, e.g. '**/.git' or '*.tmp'
+    ///
+    /// May be given more than once.  Matching a directory skips everything under it.
+    pub exclude: Vec<String>,
+
+    #[structopt(long, number_of_values = 1)]
+    /// Where change events go: log, jsonl:PATH, syslog[:SOCKET] or exec:COMMAND
+    ///
+    /// May be given more than once to send events to several places.  Defaults to log.
+    /// jsonl appends one JSON object per event.  syslog sends to /dev/log unless a socket is
+    /// given.  exec runs COMMAND with sh -c per event, with the event as JSON on stdin and
+    /// SHAFILES_EVENT, SHAFILES_PATH, SHAFILES_OLD_SHA and SHAFILES_NEW_SHA set, off the
+    /// scanning threads and within --hook-jobs and --hook-timeout like --on-change.
+    pub sink: Vec<SinkSpec>,
+
+    #[structopt(long)]
+    /// Command to run for each change, via sh -c, off the scanning threads
+    ///
+    /// SHAFILES_EVENT, SHAFILES_PATH, SHAFILES_OLD_SHA and SHAFILES_NEW_SHA are set and the event
+    /// is on stdin as JSON.  Failures are listed in the run summary.
+    pub on_change: Option<String>,
+
+    #[structopt(long)]
+    /// Command to run via sh -c once the state is saved, with the run summary as JSON on stdin
+    pub on_complete: Option<String>,
+
+    #[structopt(long)]
+    /// Max on change hooks running at once [default: 4]
+    pub hook_jobs: Option<usize>,
 
+    #[structopt(long)]
+    /// Seconds before a hook is killed [default: 60]
+    pub hook_timeout: Option<u64>,
+
+    #[structopt(long, possible_values(ReadOrder::VARIANTS))]
+    /// Order to hash the files of each directory in [default: walk]
+    ///
+    /// "walk" is whatever order the directory listing returns.  "inode" sorts each directory's
+    /// files by inode number and "extent" sorts them by the physical location of their first
+    /// block (FIEMAP, linux only - falls back to inode).  The sorted orders are meant for
+    /// spinning disks and only hold with one sha thread, so they default to -s 1.  With more,
+    /// given or added by --auto-tune, files are read roughly in order.
+    pub read_order: Option<ReadOrder>,
+
+    #[structopt(long)]
+    /// Give each device (st_dev) its own queue and pool of sha threads
+    ///
+    /// Each pool gets --threads-sha threads unless set by --device-threads.  This keeps a slow
+    /// or failing disk from tying up the workers for every other disk in the tree.
+    pub per_device: bool,
+
+    #[structopt(long, conflicts_with = "per-device")]
+    /// Use one sha thread pool for all devices even if the profile has one per device
+    pub no_per_device: bool,
+
+    #[structopt(long, number_of_values = 1)]
+    /// Sha thread count for one device as PATH=N, e.g. /mnt/disk3=1
+    ///
+    /// PATH is any path on the device, typically its mount point.  May be given more than once.
+    /// Only used with --per-device.
+    pub device_threads: Vec<DeviceThreads>,
+
+    #[structopt(long)]
+    /// Read buffer size for each sha thread, e.g. 512K, 8M [default: 64M]
+    pub buffer_size: Option<ByteSize>,
+
+    #[structopt(long)]
+    /// Max files waiting to be hashed in all sha queues together, 0 for unbounded [default: 10000]
+    ///
+    /// Directory threads block when the queues are full instead of piling paths up in memory.
+    pub file_queue: Option<usize>,
+
+    #[structopt(long)]
+    /// Max hashed entries waiting to be recorded in the state, 0 for unbounded [default: 10000]
+    pub state_queue: Option<usize>,
+
+    #[structopt(long)]
+    /// Memory budget, e.g. 256M - queues, buffers and then sha threads are cut down to fit
+    ///
+    /// This is an estimate of the scanning pipeline only.  The state itself is not counted.
+    /// Counts from --device-threads are not reduced, but their buffers are.
+    pub max_memory: Option<ByteSize>,
+
+    #[structopt(long, possible_values(ProgressMode::VARIANTS))]
+    /// How to show progress: auto, line, log or off [default: auto]
+    ///
+    /// "line" redraws a single status line on stderr every second, "log" writes a PROGRESS log
+    /// line every --progress-secs.  "auto" picks line when stderr is a terminal.
+    pub progress: Option<ProgressMode>,
+
+    #[structopt(long)]
+    /// Seconds between PROGRESS log lines [default: 10]
+    pub progress_secs: Option<u64>,
+
+}
+
+impl ScanOpts {
+    /// the flags given as a profile, to lay over one from the config
+    pub fn to_profile(&self) -> Profile {
+        fn list<T: Clone>(v: &[T]) -> Option<Vec<T>> {
+            if v.is_empty() { None } else { Some(v.to_vec()) }
+        }
+        Profile {
+            roots: self.top_dir.clone(),
+            state_path: self.state_path.clone(),
+            every: None,
+            exclude: list(&self.exclude),
+            accept_corruption: list(&self.accept_corruption),
+            sinks: list(&self.sink),
+            hash: self.hash,
+            threads_dir: self.threads_dir,
+            threads_sha: self.threads_sha,
+            auto_tune: either(self.auto_tune, self.no_auto_tune),
+            per_device: either(self.per_device, self.no_per_device),
+            device_threads: list(&self.device_threads),
+            read_order: self.read_order,
+            follow_symlinks: either(self.follow_symlinks, self.no_follow_symlinks),
+            buffer_size: self.buffer_size,
+            file_queue: self.file_queue,
+            state_queue: self.state_queue,
+            max_memory: self.max_memory,
+            progress: self.progress,
+            progress_secs: self.progress_secs,
+            on_change: self.on_change.clone(),
+            on_complete: self.on_complete.clone(),
+            hook_jobs: self.hook_jobs,
+            hook_timeout: self.hook_timeout,
+            wait_lock: self.wait_lock,
+            keep_backups: self.keep_backups,
+            from_backup: if self.from_backup { Some(true) } else { None },
+            hmac_key: self.hmac_key.clone(),
+            signing_key: self.signing_key.clone(),
+            verify_key: self.verify_key.clone(),
+            init: if self.init { Some(true) } else { None },
+            state_key: self.state_key.clone(),
+            passphrase_file: self.passphrase_file.clone(),
+            baseline: self.baseline.clone(),
+            policy: self.policy.clone(),
+            fail_on_error: either(self.fail_on_error, self.no_fail_on_error),
+            read_retries: self.read_retries,
+            settle: self.settle,
+        }
+    }
+
+    /// the profile named by --profile, if any, overridden by the flags given
+    pub fn resolve(&self, config: &Path) -> Result<Profile> {
+        let flags = self.to_profile();
+        match &self.profile {
+            None => Ok(flags),
+            Some(name) => Ok(flags.overlay(Config::load(config)?.profile(name)?)),
+        }
+    }
+}
+
+/// a boolean profile option as given by its flag and --no- flag, none when neither was
+fn either(yes: bool, no: bool) -> Option<bool> {
+    match (yes, no) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    }
+}
+
+/// subcommand names, anything else after the global options is taken as scan's options
+const COMMANDS: &[&str] = &["scan", "watch", "daemon", "ctl", "rekey", "accept", "dupes", "help"];
+
+///
+/// The arguments with `scan` put in before the first one that is not a global option or command
+///
+/// Keeps the command line from before subcommands working, e.g. `shafiles -t /etc -p etc.json`.
+fn default_to_scan(mut args: Vec<OsString>) -> Vec<OsString> {
+    let mut i = 1;
+    while let Some(arg) = args.get(i).map(|a| a.to_string_lossy()) {
+        match arg.as_ref() {
+            "-c" | "--config" => i += 2,
+            a if a.starts_with("--config=") || (a.len() > 1 && a.starts_with('-') && a[1..].chars().all(|c| c == 'v')) => i += 1,
+            a if COMMANDS.contains(&a) || ["-h", "--help", "-V", "--version"].contains(&a) => return args,
+            _ => {
+                args.insert(i, OsString::from("scan"));
+                return args;
+            }
+        }
+    }
+    args
 }
 
 pub fn get_cli() -> Cli {
-    let mut cli = Cli::from_args();
+    let mut cli = Cli::from_iter(default_to_scan(env::args_os().collect()));
     if cli.verbosity == 0 {
         cli.verbosity = 2;
     }
     cli
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        default_to_scan(line.split(' ').map(OsString::from).collect()).into_iter()
+            .map(|a| a.into_string().unwrap()).collect()
+    }
+
+    fn words(line: &str) -> Vec<&str> {
+        line.split(' ').collect()
+    }
+
+    #[test]
+    fn flat_options_are_scans() {
+        assert_eq!(args("shafiles -t /etc -d 2 -s 1 -p etc.json"), words("shafiles scan -t /etc -d 2 -s 1 -p etc.json"));
+        assert_eq!(args("shafiles -vv -c x.toml -t /etc"), words("shafiles -vv -c x.toml scan -t /etc"));
+        for line in ["shafiles", "shafiles -v", "shafiles watch -t /etc", "shafiles --config=x.toml daemon", "shafiles --help"] {
+            assert_eq!(args(line), words(line));
+        }
+        Cli::from_iter_safe(args("shafiles -t /etc -p etc.json")).unwrap();
+    }
+
+    #[test]
+    fn no_flags_turn_profile_options_off() {
+        let profile = Profile { per_device: Some(true), auto_tune: Some(true), ..Profile::default() };
+        let cli = Cli::from_iter_safe(words("shafiles scan --no-per-device --follow-symlinks")).unwrap();
+        let flags = match cli.cmd {
+            Command::Scan(opts) => opts.to_profile(),
+            cmd => panic!("not a scan: {:?}", cmd),
+        };
+        let merged = flags.overlay(&profile);
+        assert_eq!(merged.per_device, Some(false));
+        assert_eq!(merged.auto_tune, Some(true));
+        assert_eq!(merged.follow_symlinks, Some(true));
+        assert_eq!(merged.fail_on_error, None);
+        assert!(Cli::from_iter_safe(words("shafiles scan --per-device --no-per-device")).is_err());
+    }
+}
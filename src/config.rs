@@ -0,0 +1,385 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Deserializer};
+
+use crate::device_pool::DeviceThreads;
+use crate::events::EventSink;
+use crate::hasher::HashAlgo;
+use crate::hooks::Hooks;
+use crate::mem_budget::ByteSize;
+use crate::policy::Policy;
+use crate::progress::ProgressMode;
+use crate::read_order::ReadOrder;
+use crate::scanner::{Scanner, Stability};
+use crate::sinks::SinkSpec;
+use crate::state_backend::JsonFile;
+use crate::state_crypt::StateCipher;
+use crate::state_seal::StateKeys;
+use crate::watch::Watcher;
+
+/// config values are written the same way as on the command line
+macro_rules! deserialize_from_str {
+    ($($t:ty),*) => {$(
+        impl<'de> Deserialize<'de> for $t {
+            fn deserialize<D: Deserializer<'de>>(d: D) -> std::result::Result<Self, D::Error> {
+                String::deserialize(d)?.parse().map_err(serde::de::Error::custom)
+            }
+        }
+    )*};
+}
+
+deserialize_from_str!(Interval, ByteSize, DeviceThreads, ReadOrder, ProgressMode, SinkSpec);
+
+///
+/// How often a profile is scanned, e.g. "90s", "30m", "6h" or "1d" - a bare number is seconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interval(pub Duration);
+
+impl FromStr for Interval {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let (num, mult) = match s.char_indices().last() {
+            Some((i, 's')) => (&s[..i], 1),
+            Some((i, 'm')) => (&s[..i], 60),
+            Some((i, 'h')) => (&s[..i], 3600),
+            Some((i, 'd')) => (&s[..i], 86400),
+            _ => (s, 1),
+        };
+        let n: u64 = num.parse().map_err(|_| anyhow!("bad interval \"{}\", expected e.g. 90s, 30m, 6h or 1d", s))?;
+        if n == 0 {
+            bail!("interval \"{}\" must be more than zero", s);
+        }
+        let secs = n.checked_mul(mult).ok_or_else(|| anyhow!("interval \"{}\" is too long", s))?;
+        Ok(Interval(Duration::from_secs(secs)))
+    }
+}
+
+///
+/// The config file, TOML
+///
+/// ```toml
+/// [daemon]
+/// socket = "/run/shafiles.sock"
+/// socket_mode = "0600"
+/// max_running = 1
+///
+/// [profile.etc]
+/// roots = ["/etc"]
+/// state_path = "/var/lib/shafiles/etc.json"
+/// every = "6h"
+/// exclude = ["**/*.swp"]
+/// hash = "sha256"
+/// threads_sha = 2
+/// sinks = ["log", "jsonl:/var/log/shafiles/etc.jsonl"]
+/// ```
+///
+/// Profile keys are the long command line options with `_` for `-`, plus `roots` for
+/// --top-dir and `sinks` for --sink.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub daemon: DaemonSettings,
+    #[serde(default)]
+    pub profile: BTreeMap<String, Profile>,
+}
+
+fn default_socket() -> PathBuf {
+    PathBuf::from("/run/shafiles.sock")
+}
+
+fn one() -> usize {
+    1
+}
+
+fn owner_only() -> u32 {
+    0o600
+}
+
+/// only the owner, or the owner and group, may talk to the daemon
+fn socket_mode<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<u32, D::Error> {
+    match String::deserialize(d)?.as_str() {
+        "0600" | "600" => Ok(0o600),
+        "0660" | "660" => Ok(0o660),
+        m => Err(serde::de::Error::custom(format!("socket_mode \"{}\" must be 0600 or 0660", m))),
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DaemonSettings {
+    #[serde(default = "default_socket")]
+    pub socket: PathBuf,
+    /// permissions of the control socket, 0600 or 0660 to let the socket's group in too
+    #[serde(default = "owner_only", deserialize_with = "socket_mode")]
+    pub socket_mode: u32,
+    /// scans run at once, the rest wait their turn
+    #[serde(default = "one")]
+    pub max_running: usize,
+}
+
+impl Default for DaemonSettings {
+    fn default() -> Self {
+        DaemonSettings { socket: default_socket(), socket_mode: owner_only(), max_running: 1 }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("cannot read config \"{}\"", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("bad config \"{}\"", path.display()))
+    }
+
+    pub fn profile(&self, name: &str) -> Result<&Profile> {
+        self.profile.get(name).ok_or_else(|| {
+            anyhow!("no profile \"{}\", known ones are: {}", name, self.profile.keys().cloned().collect::<Vec<_>>().join(", "))
+        })
+    }
+}
+
+///
+/// Everything about how to scan some roots, unset values take the usual defaults
+///
+/// A profile from the config file is overlaid by one made from the command line, so flags
+/// given there win.  Lists given on the command line replace the profile's list.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    #[serde(default)]
+    pub roots: Vec<PathBuf>,
+    pub state_path: Option<PathBuf>,
+    /// time between daemon scans, none to only scan when asked
+    pub every: Option<Interval>,
+    pub exclude: Option<Vec<String>>,
+    pub accept_corruption: Option<Vec<String>>,
+    pub sinks: Option<Vec<SinkSpec>>,
+    pub hash: Option<HashAlgo>,
+    pub threads_dir: Option<usize>,
+    pub threads_sha: Option<usize>,
+    pub auto_tune: Option<bool>,
+    pub per_device: Option<bool>,
+    pub device_threads: Option<Vec<DeviceThreads>>,
+    pub read_order: Option<ReadOrder>,
+    pub follow_symlinks: Option<bool>,
+    pub buffer_size: Option<ByteSize>,
+    pub file_queue: Option<usize>,
+    pub state_queue: Option<usize>,
+    pub max_memory: Option<ByteSize>,
+    pub progress: Option<ProgressMode>,
+    pub progress_secs: Option<u64>,
+    pub on_change: Option<String>,
+    pub on_complete: Option<String>,
+    pub hook_jobs: Option<usize>,
+    pub hook_timeout: Option<u64>,
+    pub wait_lock: Option<u64>,
+    pub keep_backups: Option<usize>,
+    pub from_backup: Option<bool>,
+    pub hmac_key: Option<PathBuf>,
+    pub signing_key: Option<PathBuf>,
+    pub verify_key: Option<PathBuf>,
+    pub init: Option<bool>,
+    pub state_key: Option<PathBuf>,
+    pub passphrase_file: Option<PathBuf>,
+    pub baseline: Option<PathBuf>,
+    pub policy: Option<PathBuf>,
+    pub fail_on_error: Option<bool>,
+    pub read_retries: Option<u32>,
+    /// seconds
+    pub settle: Option<u64>,
+}
+
+impl Profile {
+    /// this profile with anything it leaves unset taken from `under`
+    pub fn overlay(&self, under: &Profile) -> Profile {
+        macro_rules! pick {
+            ($($f:ident),*) => { Profile {
+                roots: if self.roots.is_empty() { under.roots.clone() } else { self.roots.clone() },
+                $($f: self.$f.clone().or_else(|| under.$f.clone()),)*
+            }};
+        }
+        pick!(state_path, every, exclude, accept_corruption, sinks, hash, threads_dir, threads_sha, auto_tune, per_device, device_threads,
+              read_order, follow_symlinks, buffer_size, file_queue, state_queue, max_memory, progress, progress_secs,
+              on_change, on_complete, hook_jobs, hook_timeout, wait_lock,
+              keep_backups, from_backup, hmac_key, signing_key, verify_key, init,
+              state_key, passphrase_file, baseline, policy, fail_on_error, read_retries, settle)
+    }
+
+    pub fn state_path(&self) -> Result<&PathBuf> {
+        self.state_path.as_ref().ok_or_else(|| anyhow!("no state path given, use -p or a profile with state_path"))
+    }
+
+    pub fn state_keys(&self) -> Result<StateKeys> {
+        let mut keys = StateKeys::default();
+        if let Some(k) = &self.hmac_key {
+            keys = keys.hmac_key_file(k)?;
+        }
+        if let Some(k) = &self.signing_key {
+            keys = keys.signing_key_file(k)?;
+        }
+        if let Some(k) = &self.verify_key {
+            keys = keys.verify_key_file(k)?;
+        }
+        Ok(keys.cipher(self.state_cipher()?))
+    }
+
+    /// what the state is encrypted with, if anything
+    pub fn state_cipher(&self) -> Result<Option<StateCipher>> {
+        match (&self.state_key, &self.passphrase_file) {
+            (Some(_), Some(_)) => bail!("give a state key or a passphrase file, not both"),
+            (Some(k), None) => Ok(Some(StateCipher::key_file(k)?)),
+            (None, Some(p)) => Ok(Some(StateCipher::passphrase_file(p)?)),
+            (None, None) => Ok(None),
+        }
+    }
+
+    pub fn state(&self) -> Result<JsonFile> {
+        Ok(JsonFile::new(self.state_path()?)
+            .keys(self.state_keys()?)
+            .baseline(self.baseline.clone())
+            .wait_lock(self.wait_lock.map(Duration::from_secs))
+            .keep_backups(self.keep_backups.unwrap_or(0))
+            .from_backup(self.from_backup.unwrap_or(false))
+            .init(self.init.unwrap_or(false)))
+    }
+
+    /// the policy file loaded, or everything at info without one
+    pub fn load_policy(&self) -> Result<Policy> {
+        match &self.policy {
+            Some(p) => Policy::load(p),
+            None => Ok(Policy::default()),
+        }
+    }
+
+    pub fn hooks(&self) -> Hooks {
+        let d = Hooks::default();
+        Hooks {
+            on_change: self.on_change.clone(),
+            on_complete: self.on_complete.clone(),
+            max_running: self.hook_jobs.unwrap_or(d.max_running),
+            timeout: self.hook_timeout.map(Duration::from_secs).unwrap_or(d.timeout),
+        }
+    }
+
+    /// the sinks opened, or the log if none are given
+    pub fn open_sinks(&self) -> Result<Vec<Box<dyn EventSink>>> {
+        match &self.sinks {
+            Some(s) if !s.is_empty() => s.iter().map(|s| s.open(&self.hooks())).collect(),
+            _ => Ok(vec![SinkSpec::Log.open(&self.hooks())?]),
+        }
+    }
+
+    /// retries and settle time for files changing as they are read
+    pub fn stability(&self) -> Stability {
+        Stability {
+            retries: self.read_retries.unwrap_or(Stability::default().retries),
+            settle: self.settle.map(Duration::from_secs),
+        }
+    }
+
+    /// a scanner walking all the roots into the one state
+    pub fn scanner(&self) -> Result<Scanner> {
+        let (first, rest) = self.roots.split_first().ok_or_else(|| anyhow!("no top dir given, use -t or a profile with roots"))?;
+        let mut scanner = Scanner::new(first, self.state()?)
+            .threads_dir(self.threads_dir)
+            .threads_sha(self.threads_sha)
+            .per_device(self.per_device.unwrap_or(false), self.device_threads.clone().unwrap_or_default())
+            .auto_tune(self.auto_tune.unwrap_or(false))
+            .read_order(self.read_order.unwrap_or(ReadOrder::Walk))
+            .follow_symlinks(self.follow_symlinks.unwrap_or(false))
+            .hash(self.hash.unwrap_or(HashAlgo::Sha1))
+            .buffer_size(self.buffer_size.unwrap_or(ByteSize(64 * 1024 * 1024)).0)
+            .queues(self.file_queue.unwrap_or(10000), self.state_queue.unwrap_or(10000))
+            .max_memory(self.max_memory.map(|m| m.0))
+            .progress(self.progress.unwrap_or(ProgressMode::Auto), Duration::from_secs(self.progress_secs.unwrap_or(10)))
+            .policy(self.load_policy()?)
+            .stability(self.stability())
+            .hooks(self.hooks());
+        for r in rest {
+            scanner = scanner.root(r);
+        }
+        for g in self.exclude.iter().flatten() {
+            scanner = scanner.exclude(g);
+        }
+        for g in self.accept_corruption.iter().flatten() {
+            scanner = scanner.accept_corruption(g);
+        }
+        for s in self.open_sinks()? {
+            scanner = scanner.sink(s);
+        }
+        Ok(scanner)
+    }
+
+    /// a watcher on the single root of this profile, settle and rescan times are left to the caller
+    pub fn watcher(&self) -> Result<Watcher> {
+        let root = match self.roots.as_slice() {
+            [root] => root,
+            [] => bail!("no top dir given, use -t or a profile with roots"),
+            _ => bail!("watch takes a single root but {} were given", self.roots.len()),
+        };
+        let mut watcher = Watcher::new(root, self.state()?)
+            .follow_symlinks(self.follow_symlinks.unwrap_or(false))
+            .hash(self.hash.unwrap_or(HashAlgo::Sha1))
+            .buffer_size(self.buffer_size.unwrap_or(ByteSize(64 * 1024 * 1024)).0)
+            .policy(self.load_policy()?)
+            .stability(self.stability())
+            .hooks(self.hooks());
+        for g in self.exclude.iter().flatten() {
+            watcher = watcher.exclude(g);
+        }
+        for g in self.accept_corruption.iter().flatten() {
+            watcher = watcher.accept_corruption(g);
+        }
+        for s in self.open_sinks()? {
+            watcher = watcher.sink(s);
+        }
+        Ok(watcher)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intervals() {
+        let secs = |s: &str| s.parse::<Interval>().map(|i| i.0.as_secs());
+        assert_eq!(secs("90").unwrap(), 90);
+        assert_eq!(secs("90s").unwrap(), 90);
+        assert_eq!(secs("30m").unwrap(), 30 * 60);
+        assert_eq!(secs(" 6h ").unwrap(), 6 * 3600);
+        assert_eq!(secs("1d").unwrap(), 86400);
+        for bad in ["", "0", "0h", "h", "1.5h", "-1m", "10w", "6 h"] {
+            assert!(secs(bad).is_err(), "{:?} parsed", bad);
+        }
+    }
+
+    #[test]
+    fn interval_overflow() {
+        let max = u64::MAX.to_string();
+        assert_eq!(max.parse::<Interval>().unwrap().0.as_secs(), u64::MAX);
+        assert!(format!("{}m", max).parse::<Interval>().is_err());
+        assert!(format!("{}d", u64::MAX / 86400 + 1).parse::<Interval>().is_err());
+        assert_eq!(format!("{}d", u64::MAX / 86400).parse::<Interval>().unwrap().0.as_secs(), u64::MAX / 86400 * 86400);
+    }
+
+    #[test]
+    fn intervals_in_profiles() {
+        let config: Config = toml::from_str("[profile.etc]\nroots = [\"/etc\"]\nevery = \"6h\"\n").unwrap();
+        assert_eq!(config.profile["etc"].every, Some(Interval(Duration::from_secs(6 * 3600))));
+        assert!(toml::from_str::<Config>("[profile.etc]\nevery = \"often\"\n").is_err());
+    }
+
+    #[test]
+    fn socket_mode_is_owner_or_group() {
+        assert_eq!(Config::default().daemon.socket_mode, 0o600);
+        let config: Config = toml::from_str("[daemon]\nsocket_mode = \"0660\"\n").unwrap();
+        assert_eq!(config.daemon.socket_mode, 0o660);
+        assert!(toml::from_str::<Config>("[daemon]\nsocket_mode = \"0666\"\n").is_err());
+    }
+}
@@ -0,0 +1,331 @@
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{sleep, spawn};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::config::{Config, Profile};
+use crate::progress::ProgressMode;
+use crate::scanner::{ScanSummary, Stats};
+
+/// when the next scheduled scan is due, going by when the state was last written, none if too far off to reach
+fn first_due(p: &Profile) -> Option<SystemTime> {
+    let every = p.every?.0;
+    match p.state_path.as_ref().map(std::fs::metadata) {
+        Some(Ok(m)) => m.modified().ok().and_then(|t| t.checked_add(every)),
+        _ => Some(SystemTime::now()),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RunState {
+    Idle,
+    Queued,
+    Running,
+}
+
+/// what the daemon knows about one profile, as given by the status request
+#[derive(Serialize)]
+pub struct ProfileStatus {
+    pub name: String,
+    pub roots: Vec<PathBuf>,
+    pub state: RunState,
+    pub next_due: Option<SystemTime>,
+    pub last_started: Option<SystemTime>,
+    pub last_finished: Option<SystemTime>,
+    pub last_error: Option<String>,
+    #[serde(skip)]
+    last_summary: Option<ScanSummary>,
+    #[serde(skip)]
+    stats: Option<Arc<Stats>>,
+}
+
+impl ProfileStatus {
+    fn to_json(&self) -> Value {
+        let mut v = serde_json::to_value(self).unwrap_or(Value::Null);
+        if let (Some(stats), Some(started)) = (&self.stats, self.last_started) {
+            v["progress"] = json!({
+                "files": stats.fc.load(Ordering::Relaxed),
+                "bytes": stats.bc.load(Ordering::Relaxed),
+                "secs": started.elapsed().map(|d| d.as_secs_f64()).unwrap_or(0.0),
+            });
+        }
+        v
+    }
+}
+
+///
+/// A request on the control socket - one JSON object per line, answered by one line
+///
+/// e.g. `{"cmd":"scan","profile":"etc"}`.  Answers always carry "ok", and "error" when it is false.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum Request {
+    /// every profile's state, schedule and progress
+    Status,
+    /// queue a scan now unless one is already queued or running
+    Scan { profile: String },
+    /// the summary of the last finished scan
+    Summary { profile: String },
+}
+
+type Profiles = Arc<Mutex<BTreeMap<String, ProfileStatus>>>;
+/// where scans are queued for the workers, taken away when the daemon stops
+type Queue = Arc<Mutex<Option<crossbeam_channel::Sender<String>>>>;
+
+/// queue a scan of `name`, false once the daemon is stopping
+fn enqueue(queue: &Queue, name: &str) -> bool {
+    queue.lock().unwrap().as_ref().is_some_and(|q| q.send(name.to_string()).is_ok())
+}
+
+///
+/// Runs scheduled and requested scans of the profiles in a config
+///
+/// Profiles with `every` set are scanned on that schedule and any profile can be scanned on
+/// request.  At most `max_running` scans run at once and a profile is never queued twice, so
+/// overlapping schedules wait instead of colliding.  Schedules pick up from the state file's
+/// modification time, so a restart does not rescan everything.
+pub struct Daemon {
+    config: Config,
+}
+
+impl Daemon {
+    pub fn new(config: Config) -> Self {
+        Daemon { config }
+    }
+
+    /// serve until `stop` is set, then wait for running scans to finish
+    pub fn run(self, stop: &AtomicBool) -> Result<()> {
+        let sock = &self.config.daemon.socket;
+        if UnixStream::connect(sock).is_ok() {
+            bail!("a daemon is already listening on \"{}\"", sock.display());
+        }
+        let _ = std::fs::remove_file(sock);
+        // anyone who can connect can start scans, so the socket is never open to others, not
+        // even between binding and the chmod
+        let umask = unsafe { libc::umask(0o177) };
+        let bound = UnixListener::bind(sock);
+        unsafe { libc::umask(umask) };
+        let listener = bound.with_context(|| format!("cannot listen on \"{}\"", sock.display()))?;
+        std::fs::set_permissions(sock, std::fs::Permissions::from_mode(self.config.daemon.socket_mode))
+            .with_context(|| format!("cannot set the mode of \"{}\"", sock.display()))?;
+        listener.set_nonblocking(true)?;
+
+        let profiles: Profiles = Arc::new(Mutex::new(self.config.profile.iter().map(|(name, p)| {
+            (name.clone(), ProfileStatus {
+                name: name.clone(),
+                roots: p.roots.clone(),
+                state: RunState::Idle,
+                next_due: first_due(p),
+                last_started: None,
+                last_finished: None,
+                last_error: None,
+                last_summary: None,
+                stats: None,
+            })
+        }).collect()));
+        let configs: Arc<BTreeMap<String, Profile>> = Arc::new(self.config.profile.iter().map(|(name, p)| {
+            let mut p = p.clone();
+            p.progress = Some(ProgressMode::Off);
+            (name.clone(), p)
+        }).collect());
+
+        // connections hold the queue too, so it is closed by taking the sender, not by dropping clones
+        let (sender, queued) = crossbeam_channel::unbounded::<String>();
+        let queue: Queue = Arc::new(Mutex::new(Some(sender)));
+        let workers: Vec<_> = (0..self.config.daemon.max_running.max(1)).map(|_| {
+            let queued = queued.clone();
+            let profiles = profiles.clone();
+            let configs = configs.clone();
+            spawn(move || {
+                for name in queued.iter() {
+                    run_profile(&name, &configs[&name], &profiles);
+                }
+            })
+        }).collect();
+        info!("daemon listening on {} for {} profiles, {} scans at a time", sock.display(), configs.len(), workers.len());
+
+        while !stop.load(Ordering::Relaxed) {
+            match listener.accept() {
+                Ok((conn, _)) => {
+                    let profiles = profiles.clone();
+                    let queue = queue.clone();
+                    spawn(move || {
+                        if let Err(e) = serve(conn, &profiles, &queue) {
+                            warn!("control connection: {:#}", e);
+                        }
+                    });
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => sleep(Duration::from_millis(200)),
+                Err(e) => error!("control socket accept: {}", e),
+            }
+            let now = SystemTime::now();
+            let mut profiles = profiles.lock().unwrap();
+            for st in profiles.values_mut() {
+                if st.state == RunState::Idle && st.next_due.is_some_and(|t| t <= now) && enqueue(&queue, &st.name) {
+                    st.state = RunState::Queued;
+                }
+            }
+        }
+
+        info!("daemon stopping, waiting for running scans");
+        {
+            // under the profiles lock, so no request is between queueing and marking its profile
+            let mut profiles = profiles.lock().unwrap();
+            queue.lock().unwrap().take();
+            for (_, st) in profiles.iter_mut().filter(|(_, st)| st.state == RunState::Queued) {
+                st.state = RunState::Idle;
+            }
+        }
+        for h in workers {
+            h.join().map_err(|_| anyhow!("scan worker panicked"))?;
+        }
+        let _ = std::fs::remove_file(sock);
+        Ok(())
+    }
+}
+
+fn run_profile(name: &str, profile: &Profile, profiles: &Profiles) {
+    {
+        let mut profiles = profiles.lock().unwrap();
+        let st = profiles.get_mut(name).unwrap();
+        // taken off the queue by a stop
+        if st.state != RunState::Queued {
+            return;
+        }
+        st.state = RunState::Running;
+        st.last_started = Some(SystemTime::now());
+    }
+    info!("scan of {} starting", name);
+    let r = profile.scanner().and_then(|scanner| {
+        profiles.lock().unwrap().get_mut(name).unwrap().stats = Some(scanner.stats());
+        scanner.run()
+    });
+
+    let mut profiles = profiles.lock().unwrap();
+    let st = profiles.get_mut(name).unwrap();
+    let now = SystemTime::now();
+    st.state = RunState::Idle;
+    st.stats = None;
+    st.last_finished = Some(now);
+    st.next_due = profile.every.and_then(|e| now.checked_add(e.0));
+    match r {
+        Ok(s) => {
+            info!("scan of {} done: {} files, {} changed, {} added, {} deleted, {} errors",
+                name, s.files, s.changed(), s.added, s.deleted, s.errors);
+            st.last_error = None;
+            st.last_summary = Some(s);
+        }
+        Err(e) => {
+            error!("scan of {} failed: {:#}", name, e);
+            st.last_error = Some(format!("{:#}", e));
+        }
+    }
+}
+
+/// answer requests on one control connection until the client hangs up
+fn serve(conn: UnixStream, profiles: &Profiles, queue: &Queue) -> Result<()> {
+    conn.set_nonblocking(false)?;
+    let mut out = conn.try_clone()?;
+    for line in BufReader::new(conn).lines() {
+        let line = line?;
+        let answer = match serde_json::from_str::<Request>(&line) {
+            Err(e) => json!({"ok": false, "error": format!("bad request: {}", e)}),
+            Ok(req) => answer(req, profiles, queue),
+        };
+        serde_json::to_writer(&mut out, &answer)?;
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+fn answer(req: Request, profiles: &Profiles, queue: &Queue) -> Value {
+    let mut profiles = profiles.lock().unwrap();
+    match req {
+        Request::Status => json!({"ok": true, "profiles": profiles.values().map(|r| r.to_json()).collect::<Vec<_>>()}),
+        Request::Scan { profile } => match profiles.get_mut(&profile) {
+            None => json!({"ok": false, "error": format!("no profile named \"{}\"", profile)}),
+            Some(st) if st.state != RunState::Idle => json!({"ok": true, "queued": false, "state": st.state}),
+            Some(st) if enqueue(queue, &profile) => {
+                st.state = RunState::Queued;
+                json!({"ok": true, "queued": true, "state": st.state})
+            }
+            Some(_) => json!({"ok": false, "error": "daemon is stopping"}),
+        },
+        Request::Summary { profile } => match profiles.get(&profile) {
+            None => json!({"ok": false, "error": format!("no profile named \"{}\"", profile)}),
+            Some(st) => json!({"ok": true, "finished": st.last_finished, "summary": st.last_summary,
+                               "last_error": st.last_error}),
+        },
+    }
+}
+
+/// send one request to a daemon and return its answer, an error if it said no
+pub fn request(socket: &Path, req: &Request) -> Result<Value> {
+    let mut conn = UnixStream::connect(socket)
+        .with_context(|| format!("cannot connect to daemon at \"{}\"", socket.display()))?;
+    serde_json::to_writer(&mut conn, req)?;
+    conn.write_all(b"\n")?;
+    let mut line = String::new();
+    BufReader::new(conn).read_line(&mut line)?;
+    let v: Value = serde_json::from_str(&line).context("bad answer from daemon")?;
+    if v["ok"] != Value::Bool(true) {
+        bail!("daemon said: {}", v["error"].as_str().unwrap_or("no reason given"));
+    }
+    Ok(v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profiles() -> Profiles {
+        let st = ProfileStatus {
+            name: "etc".to_string(),
+            roots: vec![PathBuf::from("/etc")],
+            state: RunState::Idle,
+            next_due: None,
+            last_started: None,
+            last_finished: None,
+            last_error: None,
+            last_summary: None,
+            stats: None,
+        };
+        Arc::new(Mutex::new(BTreeMap::from([("etc".to_string(), st)])))
+    }
+
+    fn scan(profiles: &Profiles, queue: &Queue) -> Value {
+        answer(Request::Scan { profile: "etc".to_string() }, profiles, queue)
+    }
+
+    #[test]
+    fn scans_are_queued_once() {
+        let (profiles, (sender, queued)) = (profiles(), crossbeam_channel::unbounded());
+        let queue = Arc::new(Mutex::new(Some(sender)));
+        assert_eq!(scan(&profiles, &queue)["queued"], true);
+        assert_eq!(scan(&profiles, &queue)["queued"], false);
+        assert_eq!(queued.try_iter().collect::<Vec<_>>(), ["etc"]);
+        assert_eq!(profiles.lock().unwrap()["etc"].state, RunState::Queued);
+    }
+
+    #[test]
+    fn no_scans_are_queued_once_stopping() {
+        let (profiles, (sender, queued)) = (profiles(), crossbeam_channel::unbounded());
+        let queue = Arc::new(Mutex::new(Some(sender)));
+        queue.lock().unwrap().take();
+        assert_eq!(scan(&profiles, &queue)["ok"], false);
+        assert_eq!(profiles.lock().unwrap()["etc"].state, RunState::Idle);
+        // the workers see the queue close even while connections still hold it
+        assert!(queued.recv().is_err());
+    }
+}
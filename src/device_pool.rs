@@ -0,0 +1,303 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use anyhow::{anyhow, Context, Result};
+use crossbeam_channel::{Receiver, RecvError, Sender};
+use log::{debug, info};
+
+use crate::mem_budget::QueueBudget;
+use crate::tuning::{self, AutoTuner};
+
+/// counters kept for each device pool so progress can be shown per disk
+#[derive(Default)]
+pub struct DevStats {
+    pub fc: AtomicUsize,
+    pub bc: AtomicUsize,
+    /// sha threads with an index below this take work, the rest idle - see auto tuning
+    pub active: AtomicUsize,
+    /// sha threads hashing a file right now
+    pub busy: AtomicUsize,
+}
+
+/// starts sha thread number `idx` reading from the pool's queue and returns its handle
+pub type Spawner = dyn Fn(FileQueue, Arc<DevStats>, usize) -> JoinHandle<usize> + Send + Sync;
+
+/// a pool's end of its file queue, each file taken off frees its room in the shared budget
+#[derive(Clone)]
+pub struct FileQueue {
+    recv: Receiver<Option<PathBuf>>,
+    budget: QueueBudget,
+}
+
+impl FileQueue {
+    pub fn recv(&self) -> Result<Option<PathBuf>, RecvError> {
+        let path = self.recv.recv()?;
+        if path.is_some() {
+            self.budget.give_back();
+        }
+        Ok(path)
+    }
+}
+
+struct Pool {
+    send: Sender<Option<PathBuf>>,
+    handles: Vec<JoinHandle<usize>>,
+    stats: Arc<DevStats>,
+    tuner: Option<AutoTuner>,
+}
+
+///
+/// Routes files to sha thread pools keyed by the device (st_dev) they live on
+///
+/// Pools are created the first time a device is seen, so a tree that spans several disks gets
+/// one pool per disk and a slow or failing disk only ties up its own threads.  When per device
+/// routing is off, everything goes to a single pool - which is the classic behavior.
+///
+/// The pools' queues are unbounded and share one bound on the files waiting in all of them, so
+/// a slow disk's backlog never stops files reaching the others until the whole bound is used.
+#[derive(Clone)]
+pub struct DevicePools {
+    pools: Arc<Mutex<BTreeMap<u64, Pool>>>,
+    per_device: bool,
+    default_threads: usize,
+    auto_threads: bool,
+    auto_tune: bool,
+    budget: QueueBudget,
+    max_threads: usize,
+    overrides: Arc<HashMap<u64, usize>>,
+    spawner: Arc<Spawner>,
+}
+
+///
+/// A `--device-threads` setting of the form PATH=N
+///
+/// Any path on the device will do, typically its mount point.
+#[derive(Debug, Clone)]
+pub struct DeviceThreads {
+    pub path: PathBuf,
+    pub threads: usize,
+}
+
+impl FromStr for DeviceThreads {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let pos = s.rfind('=').ok_or_else(|| anyhow!("expected PATH=N but got \"{}\"", s))?;
+        let threads = s[pos + 1..].parse().with_context(|| format!("bad thread count in \"{}\"", s))?;
+        if threads == 0 {
+            return Err(anyhow!("thread count for \"{}\" must be at least 1", &s[..pos]));
+        }
+        Ok(DeviceThreads { path: PathBuf::from(&s[..pos]), threads })
+    }
+}
+
+impl DevicePools {
+    ///
+    /// Pools get `default_threads` sha threads unless overridden per device.  With `auto_threads`
+    /// each device pool picks its own count from whether the device is rotational instead.
+    /// With `auto_tune` each pool starts twice the threads and `tune` varies how many are active,
+    /// parked threads holding no buffer.
+    pub fn new(per_device: bool, default_threads: usize, auto_threads: bool, auto_tune: bool, queue_cap: usize,
+               dev_threads: &[DeviceThreads], spawner: Box<Spawner>) -> Result<Self> {
+        let mut overrides = HashMap::new();
+        for dt in dev_threads {
+            let dev = device_of(&dt.path)?;
+            debug!("device {} from {} gets {} sha threads", dev_name(dev), dt.path.display(), dt.threads);
+            overrides.insert(dev, dt.threads);
+        }
+        Ok(DevicePools {
+            pools: Arc::new(Mutex::new(BTreeMap::new())),
+            per_device,
+            default_threads,
+            auto_threads,
+            auto_tune,
+            budget: QueueBudget::new(queue_cap),
+            max_threads: usize::MAX,
+            overrides: Arc::new(overrides),
+            spawner: Arc::from(spawner),
+        })
+    }
+
+    /// no pool runs more sha threads than this, counting those auto tuning may make active
+    pub fn max_threads(mut self, n: usize) -> Self {
+        self.max_threads = n.max(1);
+        self
+    }
+
+    /// hand a file on device `dev` to that device's pool, creating the pool if needed
+    pub fn send(&self, dev: u64, path: PathBuf) -> Result<()> {
+        let key = if self.per_device { dev } else { 0 };
+        let send = {
+            let mut pools = self.pools.lock().unwrap();
+            pools.entry(key).or_insert_with(|| self.start_pool(key)).send.clone()
+        };
+        self.budget.take();
+        send.send(Some(path))?;
+        Ok(())
+    }
+
+    fn start_pool(&self, key: u64) -> Pool {
+        let threads = match self.overrides.get(&key) {
+            Some(n) => *n,
+            None if self.per_device && self.auto_threads => tuning::default_sha_threads(tuning::is_rotational(key)),
+            None => self.default_threads,
+        }.min(self.max_threads);
+        let (spawned, tuner) = if self.auto_tune {
+            let max = (threads * 2).max(2).min(self.max_threads).max(threads);
+            (max, Some(AutoTuner::new(threads, max)))
+        } else {
+            (threads, None)
+        };
+        if self.per_device {
+            info!("starting {} sha threads for device {}", threads, dev_name(key));
+        }
+        let (send, recv) = crossbeam_channel::unbounded();
+        let queue = FileQueue { recv, budget: self.budget.clone() };
+        let stats = Arc::new(DevStats::default());
+        stats.active.store(threads, Ordering::Relaxed);
+        let handles = (0..spawned).map(|i| (self.spawner)(queue.clone(), stats.clone(), i)).collect();
+        Pool { send, handles, stats, tuner }
+    }
+
+    fn pool_name(&self, key: u64) -> String {
+        if self.per_device { format!("device {}", dev_name(key)) } else { String::from("all devices") }
+    }
+
+    /// one auto tuning step for every pool, `secs` since the last step
+    pub fn tune(&self, secs: f64) {
+        let mut pools = self.pools.lock().unwrap();
+        for (dev, pool) in pools.iter_mut() {
+            if let Some(tuner) = pool.tuner.as_mut() {
+                let active = pool.stats.active.load(Ordering::Relaxed);
+                let next = tuner.step(pool.stats.bc.load(Ordering::Relaxed), secs, active);
+                if next != active {
+                    debug!("auto tune {}: active sha threads {} -> {}", self.pool_name(*dev), active, next);
+                    pool.stats.active.store(next, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    /// per device file and byte counts for progress reporting, empty unless routing per device
+    pub fn device_stats(&self) -> Vec<(String, usize, usize)> {
+        if !self.per_device {
+            return vec![];
+        }
+        let pools = self.pools.lock().unwrap();
+        pools.iter()
+            .map(|(dev, p)| (dev_name(*dev), p.stats.fc.load(Ordering::Relaxed), p.stats.bc.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// files waiting in all sha queues
+    pub fn queue_depth(&self) -> usize {
+        self.pools.lock().unwrap().values().map(|p| p.send.len()).sum()
+    }
+
+    /// sha threads hashing a file right now across all pools
+    pub fn busy(&self) -> usize {
+        self.pools.lock().unwrap().values().map(|p| p.stats.busy.load(Ordering::Relaxed)).sum()
+    }
+
+    /// tell every pool to stop once its queue drains, wait for them and return total bytes hashed
+    pub fn finish(&self) -> Result<usize> {
+        // pools stay in the map so their counters can still be read while the queues drain
+        let pools: Vec<_> = self.pools.lock().unwrap().iter_mut()
+            .map(|(dev, p)| (*dev, p.send.clone(), std::mem::take(&mut p.handles), p.stats.clone(), p.tuner.take()))
+            .collect();
+        let mut tot_bytes = 0;
+        for (dev, send, handles, stats, tuner) in pools {
+            // idle threads must wake up to see their stop message
+            stats.active.store(usize::MAX, Ordering::Relaxed);
+            let nthreads = handles.len();
+            for _ in 0..nthreads { send.send(None)?; }
+            let mut bytes = 0;
+            for h in handles {
+                bytes += h.join().map_err(|_| anyhow!("sha thread for device {} panicked", dev_name(dev)))?;
+            }
+            if self.per_device {
+                info!("device {} done: {} files {} bytes", dev_name(dev), stats.fc.load(Ordering::Relaxed), bytes);
+            }
+            if let Some(tuner) = tuner {
+                info!("auto tune {}: best rate {:.2} MB/sec with {} of {} sha threads active",
+                    self.pool_name(dev), tuner.best_rate / (1024.0 * 1024.0), tuner.best_active, nthreads);
+            }
+            tot_bytes += bytes;
+        }
+        Ok(tot_bytes)
+    }
+}
+
+#[cfg(unix)]
+pub fn device_of(path: &Path) -> Result<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Ok(std::fs::metadata(path).with_context(|| format!("cannot stat \"{}\" to find its device", path.display()))?.dev())
+}
+
+#[cfg(not(unix))]
+pub fn device_of(_path: &Path) -> Result<u64> {
+    Ok(0)
+}
+
+///
+/// The devices files under `roots` are on, so the pools routing per device will start
+///
+/// Those of the roots themselves and of filesystems mounted below them, from
+/// /proc/self/mountinfo.  Symlinks followed out of the roots can reach others.
+pub fn devices_under(roots: &[PathBuf]) -> BTreeSet<u64> {
+    let roots: Vec<PathBuf> = roots.iter().filter_map(|r| std::fs::canonicalize(r).ok()).collect();
+    let mut devs: BTreeSet<u64> = roots.iter().filter_map(|r| device_of(r).ok()).collect();
+    let mounts = std::fs::read_to_string("/proc/self/mountinfo").unwrap_or_default();
+    for line in mounts.lines() {
+        if let Some(point) = line.split(' ').nth(4).map(unescape_mount) {
+            if roots.iter().any(|r| point.starts_with(r)) {
+                devs.extend(device_of(&point).ok());
+            }
+        }
+    }
+    devs
+}
+
+/// a mountinfo path, where space, tab, newline and backslash are written as octal escapes
+fn unescape_mount(s: &str) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+    let b = s.as_bytes();
+    let mut out = Vec::with_capacity(b.len());
+    let mut i = 0;
+    while i < b.len() {
+        let oct = b.get(i + 1..i + 4).and_then(|o| std::str::from_utf8(o).ok()).and_then(|o| u8::from_str_radix(o, 8).ok());
+        match (b[i], oct) {
+            (b'\\', Some(c)) => {
+                out.push(c);
+                i += 4;
+            }
+            (c, _) => {
+                out.push(c);
+                i += 1;
+            }
+        }
+    }
+    PathBuf::from(std::ffi::OsString::from_vec(out))
+}
+
+/// major:minor form of a device number
+pub fn dev_name(dev: u64) -> String {
+    let dev = dev as libc::dev_t;
+    format!("{}:{}", libc::major(dev), libc::minor(dev))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dev_name_of_large_numbers() {
+        assert_eq!(dev_name(libc::makedev(8, 1) as u64), "8:1");
+        assert_eq!(dev_name(libc::makedev(4095, 1048575) as u64), "4095:1048575");
+        assert_eq!(dev_name(libc::makedev(259, 300) as u64), "259:300");
+    }
+}
@@ -0,0 +1,298 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use log::warn;
+use serde::Serialize;
+
+use crate::hasher::HashValue;
+use crate::sha_state::{FileKind, ShaSet};
+
+/// how much of each file is compared at a time when confirming
+const COMPARE_CHUNK: usize = 64 * 1024;
+
+/// how a duplicate report is written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+    /// one row per path, the group's columns repeated on each
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            _ => Err(anyhow!("unknown format \"{}\", expected text, json or csv", s)),
+        }
+    }
+}
+
+impl Format {
+    pub const VARIANTS: &'static [&'static str] = &["text", "json", "csv"];
+}
+
+/// paths whose state entries have the same size and digest
+#[derive(Debug, Clone, Serialize)]
+pub struct Group {
+    pub size: u64,
+    pub sha: HashValue,
+    /// how many separate copies are on disk - fewer than paths when some are hard links
+    /// of each other
+    pub copies: usize,
+    pub wasted: u64,
+    pub paths: Vec<PathBuf>,
+}
+
+impl Group {
+    fn new(size: u64, sha: HashValue, copies: usize, mut paths: Vec<PathBuf>) -> Self {
+        paths.sort();
+        Group { size, sha, copies, wasted: size * (copies as u64 - 1), paths }
+    }
+}
+
+/// the paths of one content, the inodes they were on when scanned and how many had none recorded
+#[derive(Default)]
+struct Found {
+    paths: Vec<PathBuf>,
+    inodes: HashSet<(u64, u64)>,
+    unknown: usize,
+}
+
+impl Found {
+    /// hard links of one inode count once, entries without an inode each count
+    fn copies(&self) -> usize {
+        self.inodes.len() + self.unknown
+    }
+}
+
+///
+/// Groups of regular files in `set` with the same size and digest, most wasted bytes first
+///
+/// Entries smaller than `min_size` are left out, and so are empty and unreadable ones and those
+/// from before sizes were kept.  Entries hashed with different algorithms never match, so a
+/// state part way through a change of algorithm finds fewer duplicates than it has.  Hard links
+/// of one inode count as one copy, going by the inodes the scan recorded.
+pub fn find(set: &ShaSet, min_size: u64) -> Vec<Group> {
+    let mut by_content: HashMap<(u64, &HashValue), Found> = HashMap::new();
+    for e in set.iter().filter(|e| e.kind() == FileKind::File && !e.is_unreadable()) {
+        if let Some(size) = e.size().filter(|&s| s > 0 && s >= min_size) {
+            let found = by_content.entry((size, e.sha())).or_default();
+            found.paths.push(e.path().to_path_buf());
+            match e.inode() {
+                Some(i) => {
+                    found.inodes.insert(i);
+                }
+                None => found.unknown += 1,
+            }
+        }
+    }
+    let mut groups: Vec<Group> = by_content.into_iter()
+        .filter(|(_, found)| found.copies() > 1)
+        .map(|((size, sha), found)| Group::new(size, sha.clone(), found.copies(), found.paths))
+        .collect();
+    sort(&mut groups);
+    groups
+}
+
+/// paths found to hold one content while confirming and the inodes they are on
+struct Content {
+    paths: Vec<PathBuf>,
+    inodes: Vec<(u64, u64)>,
+}
+
+///
+/// Compare the files of each group byte by byte, splitting it where their content differs
+///
+/// Hard links of a file already in a group join it without being read, and count as one copy.
+/// Files that are gone or unreadable are dropped with a warning, as are groups left with one
+/// copy.
+pub fn confirm(groups: Vec<Group>) -> Vec<Group> {
+    let mut confirmed = vec![];
+    for Group { size, sha, paths, .. } in groups {
+        let mut same: Vec<Content> = vec![];
+        for path in paths {
+            let md = match std::fs::metadata(&path) {
+                Ok(md) => md,
+                Err(e) => {
+                    warn!("left out of duplicates, cannot stat \"{}\": {}", path.display(), e);
+                    continue;
+                }
+            };
+            let inode = (md.dev(), md.ino());
+            if let Some(c) = same.iter_mut().find(|c| c.inodes.contains(&inode)) {
+                c.paths.push(path);
+                continue;
+            }
+            match copy_of(&same, &path) {
+                Ok(Some(i)) => {
+                    same[i].paths.push(path);
+                    same[i].inodes.push(inode);
+                }
+                Ok(None) => same.push(Content { paths: vec![path], inodes: vec![inode] }),
+                Err(e) => warn!("left out of duplicates, cannot compare \"{}\": {}", path.display(), e),
+            }
+        }
+        confirmed.extend(same.into_iter()
+            .filter(|c| c.inodes.len() > 1)
+            .map(|c| Group::new(size, sha.clone(), c.inodes.len(), c.paths)));
+    }
+    sort(&mut confirmed);
+    confirmed
+}
+
+/// which of the contents found so far `path` has, comparing against the first path of each
+fn copy_of(same: &[Content], path: &Path) -> io::Result<Option<usize>> {
+    for (i, c) in same.iter().enumerate() {
+        if same_bytes(&c.paths[0], path)? {
+            return Ok(Some(i));
+        }
+    }
+    Ok(None)
+}
+
+fn sort(groups: &mut [Group]) {
+    groups.sort_by(|a, b| b.wasted.cmp(&a.wasted).then_with(|| a.paths.cmp(&b.paths)));
+}
+
+/// the two files have the same length and bytes
+fn same_bytes(a: &Path, b: &Path) -> io::Result<bool> {
+    let (mut fa, mut fb) = (File::open(a)?, File::open(b)?);
+    if fa.metadata()?.len() != fb.metadata()?.len() {
+        return Ok(false);
+    }
+    let (mut ba, mut bb) = (vec![0; COMPARE_CHUNK], vec![0; COMPARE_CHUNK]);
+    loop {
+        let n = fill(&mut fa, &mut ba)?;
+        if n != fill(&mut fb, &mut bb)? || ba[..n] != bb[..n] {
+            return Ok(false);
+        }
+        if n == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+/// read until `buf` is full or the file ends
+fn fill(f: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match f.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(m) => n += m,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+/// write the groups out in `format`
+pub fn write(groups: &[Group], format: Format, w: &mut dyn Write) -> Result<()> {
+    match format {
+        Format::Text => {
+            for g in groups {
+                writeln!(w, "{} bytes wasted: {} copies of {} bytes {}", g.wasted, g.copies, g.size, g.sha)?;
+                for p in &g.paths {
+                    writeln!(w, "  {}", p.display())?;
+                }
+            }
+        }
+        Format::Json => {
+            serde_json::to_writer_pretty(&mut *w, groups)?;
+            writeln!(w)?;
+        }
+        Format::Csv => {
+            writeln!(w, "wasted,size,sha,copies,path")?;
+            for g in groups {
+                for p in &g.paths {
+                    writeln!(w, "{},{},{},{},{}", g.wasted, g.size, g.sha, g.copies, csv_field(&p.to_string_lossy()))?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// quoted when it holds a comma, quote or line break
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sha_state::testing::{entry, set, Scratch, EMPTY, SHA_A, SHA_B};
+
+    #[test]
+    fn groups_are_entries_of_one_size_and_digest() {
+        let set = set([
+            entry("/a/1", SHA_A, 100), entry("/a/2", SHA_A, 100), entry("/a/3", SHA_A, 100),
+            entry("/b/1", SHA_B, 1000), entry("/b/2", SHA_B, 1000),
+            // the same digest at another size is not the same content
+            entry("/c/1", SHA_A, 99),
+            entry("/d/1", EMPTY, 0), entry("/d/2", EMPTY, 0),
+        ]);
+        let groups = find(&set, 0);
+        assert_eq!(groups.iter().map(|g| (g.copies, g.wasted)).collect::<Vec<_>>(), [(2, 1000), (3, 200)]);
+        assert_eq!(groups[1].paths, [PathBuf::from("/a/1"), PathBuf::from("/a/2"), PathBuf::from("/a/3")]);
+        assert_eq!(find(&set, 101).len(), 1);
+    }
+
+    #[test]
+    fn hard_links_count_as_one_copy() {
+        let dir = Scratch::new();
+        let (a, b) = (dir.file("a", b"same"), dir.file("b", b"same"));
+        let a2 = dir.path("a2");
+        std::fs::hard_link(&a, &a2).unwrap();
+        let scanned = |p: &PathBuf| entry(p.to_str().unwrap(), SHA_A, 4).with_meta(&std::fs::metadata(p).unwrap());
+
+        let groups = find(&set([scanned(&a), scanned(&a2), scanned(&b)]), 0);
+        assert_eq!((groups[0].copies, groups[0].wasted, groups[0].paths.len()), (2, 4, 3));
+        assert!(find(&set([scanned(&a), scanned(&a2)]), 0).is_empty());
+        // entries from before inodes were kept each count
+        assert_eq!(find(&set([scanned(&a), entry(a2.to_str().unwrap(), SHA_A, 4)]), 0)[0].copies, 2);
+    }
+
+    #[test]
+    fn confirm_reads_the_files() {
+        let dir = Scratch::new();
+        let (a, b, c) = (dir.file("a", b"same"), dir.file("b", b"same"), dir.file("c", b"diff"));
+        let a2 = dir.path("a2");
+        std::fs::hard_link(&a, &a2).unwrap();
+        let group = |paths: Vec<PathBuf>| Group::new(4, SHA_A.parse().unwrap(), paths.len(), paths);
+
+        // c differs after all and the missing file is dropped, a2 joins a without being a copy
+        let confirmed = confirm(vec![group(vec![a.clone(), b.clone(), c, a2.clone(), dir.path("gone")])]);
+        assert_eq!(confirmed.len(), 1);
+        assert_eq!((confirmed[0].copies, confirmed[0].wasted), (2, 4));
+        assert_eq!(confirmed[0].paths, [a.clone(), a2.clone(), b]);
+        assert!(confirm(vec![group(vec![a, a2])]).is_empty());
+    }
+
+    #[test]
+    fn csv_quotes_awkward_paths() {
+        assert_eq!(csv_field("/srv/plain"), "/srv/plain");
+        assert_eq!(csv_field("/srv/a,b"), "\"/srv/a,b\"");
+        assert_eq!(csv_field("/srv/say \"hi\""), "\"/srv/say \"\"hi\"\"\"");
+        assert_eq!(csv_field("/srv/two\nlines"), "\"/srv/two\nlines\"");
+
+        let mut out = vec![];
+        write(&find(&set([entry("/a,1", SHA_A, 10), entry("/a2", SHA_A, 10)]), 0), Format::Csv, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   format!("wasted,size,sha,copies,path\n10,10,{0},2,\"/a,1\"\n10,10,{0},2,/a2\n", SHA_A));
+    }
+}
@@ -0,0 +1,287 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use anyhow::Result;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::hasher::HashValue;
+use crate::policy::{Policy, Severity};
+use crate::sha_state::FileKind;
+
+///
+/// A change found by a scan, as handed to every `EventSink`
+///
+/// Serializes as a JSON object with an "event" field naming the variant, e.g.
+/// `{"event":"content_changed","path":"/etc/passwd",...}`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// a file not in the prior state
+    Added { path: PathBuf, sha: HashValue, size: u64 },
+    /// content differs from the prior state
+    ContentChanged { path: PathBuf, old_sha: HashValue, new_sha: HashValue, mtime_changed: bool },
+    /// content differs with the same mtime and size, and a re-read from disk agrees - bit rot
+    /// or an edit made to hide itself
+    Corruption { path: PathBuf, old_sha: HashValue, new_sha: HashValue, size: u64 },
+    /// same content but the modification time moved
+    MetadataChanged { path: PathBuf, sha: HashValue, old_mtime: SystemTime, new_mtime: SystemTime },
+    /// in the prior state but no longer found
+    Deleted { path: PathBuf, sha: HashValue },
+    /// a deleted file whose content turned up at a new path
+    Moved { from: PathBuf, to: PathBuf, sha: HashValue },
+    /// the permission bits or owner (uid, gid) changed
+    PermsChanged { path: PathBuf, old_mode: Option<u32>, new_mode: Option<u32>,
+                   old_owner: Option<(u32, u32)>, new_owner: Option<(u32, u32)> },
+    /// the number of hard links to a file changed
+    LinksChanged { path: PathBuf, old_links: u64, new_links: u64 },
+    /// an append only file grew and what it held before is unchanged
+    Appended { path: PathBuf, old_sha: HashValue, new_sha: HashValue, old_size: u64, new_size: u64 },
+    /// an append only file whose earlier content was changed
+    Rewritten { path: PathBuf, old_sha: HashValue, new_sha: HashValue, old_size: u64, new_size: u64 },
+    /// an append only file that shrank
+    Truncated { path: PathBuf, old_sha: HashValue, new_sha: HashValue, old_size: u64, new_size: u64 },
+    /// the entry is now another type of thing, e.g. a file replaced with a symlink
+    TypeChanged { path: PathBuf, old_kind: FileKind, new_kind: FileKind },
+    /// a symlink points somewhere else, or a device node has new major:minor numbers
+    TargetChanged { path: PathBuf, old_target: String, new_target: String },
+    /// the file was changing as it was read and kept its prior entry
+    Unstable { path: PathBuf, reason: String },
+    /// the path could not be read or hashed, `offset` is how far into the file a failed read got
+    Error { path: PathBuf, kind: ErrorKind, error: String,
+            #[serde(skip_serializing_if = "Option::is_none")] offset: Option<u64> },
+}
+
+/// what failed for a path that could not be scanned
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// listing a directory, everything under it keeps its prior entries
+    ReadDir,
+    Stat,
+    Open,
+    Read,
+}
+
+impl ErrorKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorKind::ReadDir => "read_dir",
+            ErrorKind::Stat => "stat",
+            ErrorKind::Open => "open",
+            ErrorKind::Read => "read",
+        }
+    }
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::Added { .. } => "added",
+            Event::ContentChanged { .. } => "content_changed",
+            Event::Corruption { .. } => "corruption",
+            Event::MetadataChanged { .. } => "metadata_changed",
+            Event::Deleted { .. } => "deleted",
+            Event::Moved { .. } => "moved",
+            Event::PermsChanged { .. } => "perms_changed",
+            Event::LinksChanged { .. } => "links_changed",
+            Event::Appended { .. } => "appended",
+            Event::Rewritten { .. } => "rewritten",
+            Event::Truncated { .. } => "truncated",
+            Event::TypeChanged { .. } => "type_changed",
+            Event::TargetChanged { .. } => "target_changed",
+            Event::Unstable { .. } => "unstable",
+            Event::Error { .. } => "error",
+        }
+    }
+
+    /// the path the event is about - the new path for a move
+    pub fn path(&self) -> &PathBuf {
+        match self {
+            Event::Added { path, .. }
+            | Event::ContentChanged { path, .. }
+            | Event::Corruption { path, .. }
+            | Event::MetadataChanged { path, .. }
+            | Event::Deleted { path, .. }
+            | Event::PermsChanged { path, .. }
+            | Event::LinksChanged { path, .. }
+            | Event::Appended { path, .. }
+            | Event::Rewritten { path, .. }
+            | Event::Truncated { path, .. }
+            | Event::TypeChanged { path, .. }
+            | Event::TargetChanged { path, .. }
+            | Event::Unstable { path, .. }
+            | Event::Error { path, .. } => path,
+            Event::Moved { to, .. } => to,
+        }
+    }
+
+    /// content hash before the change, if there was one
+    pub fn old_sha(&self) -> Option<&HashValue> {
+        match self {
+            Event::ContentChanged { old_sha, .. } | Event::Corruption { old_sha, .. } | Event::Appended { old_sha, .. }
+            | Event::Rewritten { old_sha, .. } | Event::Truncated { old_sha, .. } => Some(old_sha),
+            Event::MetadataChanged { sha, .. } | Event::Deleted { sha, .. } | Event::Moved { sha, .. } => Some(sha),
+            _ => None,
+        }
+    }
+
+    /// content hash after the change, if there is one
+    pub fn new_sha(&self) -> Option<&HashValue> {
+        match self {
+            Event::Added { sha, .. } | Event::MetadataChanged { sha, .. } | Event::Moved { sha, .. } => Some(sha),
+            Event::ContentChanged { new_sha, .. } | Event::Corruption { new_sha, .. } | Event::Appended { new_sha, .. }
+            | Event::Rewritten { new_sha, .. } | Event::Truncated { new_sha, .. } => Some(new_sha),
+            _ => None,
+        }
+    }
+}
+
+/// the event as JSON with a "severity" field added, as the sinks write it
+pub fn event_json(ev: &Event, severity: Severity) -> Value {
+    let mut v = serde_json::to_value(ev).unwrap_or(Value::Null);
+    v["severity"] = json!(severity);
+    v
+}
+
+///
+/// Somewhere change events go
+///
+/// Sinks are called from the single state recording thread, in the order changes are found,
+/// so they do not need to be thread safe.  Moves, additions and deletions are only known once
+/// the walk is done and come at the end of the run.  Each event comes with its severity
+/// under the policy, ignored ones never reach a sink.
+pub trait EventSink: Send {
+    fn event(&mut self, ev: &Event, severity: Severity) -> Result<()>;
+
+    /// called once after the last event of a run
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// where failures that do not come back from `event` are collected, for the run summary
+    fn failures(&self) -> Option<Arc<Mutex<Vec<String>>>> {
+        None
+    }
+}
+
+/// the classic warn! log lines, alerts at error level and marked as such
+pub struct LogSink;
+
+impl EventSink for LogSink {
+    fn event(&mut self, ev: &Event, severity: Severity) -> Result<()> {
+        let msg = match ev {
+            Event::Added { .. } if severity < Severity::Alert => return Ok(()),
+            Event::Added { path, .. } => format!("ADDED: {}", path.display()),
+            Event::ContentChanged { path, old_sha, new_sha, mtime_changed: true } =>
+                format!("SHA TIME CHANGE: {} {} -> {}", path.display(), old_sha, new_sha),
+            Event::ContentChanged { path, old_sha, new_sha, mtime_changed: false } =>
+                format!("SHA CHANGE: {} {} -> {}", path.display(), old_sha, new_sha),
+            Event::Corruption { path, old_sha, new_sha, .. } =>
+                format!("CORRUPTION: {} {} -> {} with mtime and size unchanged", path.display(), old_sha, new_sha),
+            Event::MetadataChanged { path, .. } => format!("TIME CHANGE: {}", path.display()),
+            Event::Deleted { path, .. } => format!("DELETED: {}", path.display()),
+            Event::Moved { from, to, .. } => format!("MOVED: {} -> {}", from.display(), to.display()),
+            Event::TypeChanged { path, old_kind, new_kind } =>
+                format!("TYPE CHANGE: {} {} -> {}", path.display(), old_kind.name(), new_kind.name()),
+            Event::TargetChanged { path, old_target, new_target } =>
+                format!("TARGET CHANGE: {} {} -> {}", path.display(), old_target, new_target),
+            Event::Unstable { path, reason } => {
+                info!("UNSTABLE: {} {}", path.display(), reason);
+                return Ok(());
+            }
+            Event::Appended { path, old_size, new_size, .. } => {
+                info!("APPENDED: {} {} -> {} bytes", path.display(), old_size, new_size);
+                return Ok(());
+            }
+            Event::Rewritten { path, old_size, new_size, .. } =>
+                format!("REWRITTEN: {} earlier content changed, {} -> {} bytes", path.display(), old_size, new_size),
+            Event::Truncated { path, old_size, new_size, .. } =>
+                format!("TRUNCATED: {} {} -> {} bytes", path.display(), old_size, new_size),
+            Event::PermsChanged { path, old_mode, new_mode, old_owner, new_owner } => {
+                let mut msg = format!("PERMS CHANGE: {}", path.display());
+                if old_mode != new_mode {
+                    msg += &format!(" mode {:o} -> {:o}", old_mode.unwrap_or(0), new_mode.unwrap_or(0));
+                }
+                if let (Some(o), Some(n)) = (old_owner, new_owner) {
+                    if o != n {
+                        msg += &format!(" owner {}:{} -> {}:{}", o.0, o.1, n.0, n.1);
+                    }
+                }
+                msg
+            }
+            Event::LinksChanged { path, old_links, new_links } =>
+                format!("LINKS CHANGE: {} {} -> {} links", path.display(), old_links, new_links),
+            Event::Error { path, kind, error, .. } => {
+                error!("ERROR: {} {} {}", kind.name(), path.display(), error);
+                return Ok(());
+            }
+        };
+        match severity {
+            Severity::Alert => error!("ALERT {}", msg),
+            _ => warn!("{}", msg),
+        }
+        Ok(())
+    }
+}
+
+/// wraps a closure so it can be used as a sink
+pub struct FnSink<F>(pub F);
+
+impl<F: FnMut(&Event) + Send> EventSink for FnSink<F> {
+    fn event(&mut self, ev: &Event, _: Severity) -> Result<()> {
+        (self.0)(ev);
+        Ok(())
+    }
+}
+
+///
+/// Fans events out to several sinks, with the severity the policy gives them
+///
+/// A failing sink is logged and does not keep the others from getting the event.  Ignored
+/// events are dropped here and alerts are counted.
+#[derive(Default)]
+pub struct Dispatcher {
+    sinks: Vec<Box<dyn EventSink>>,
+    policy: Policy,
+    pub alerts: usize,
+}
+
+impl Dispatcher {
+    pub fn new(sinks: Vec<Box<dyn EventSink>>, policy: Policy) -> Self {
+        Dispatcher { sinks, policy, alerts: 0 }
+    }
+
+    pub fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
+    pub fn emit(&mut self, ev: &Event) {
+        self.emit_at_most(ev, Severity::Alert)
+    }
+
+    /// emit `ev` at its policy's severity but no higher than `cap`
+    pub fn emit_at_most(&mut self, ev: &Event, cap: Severity) {
+        let severity = self.policy.severity_of(ev).min(cap);
+        match severity {
+            Severity::Ignore => return,
+            Severity::Alert => self.alerts += 1,
+            Severity::Info => (),
+        }
+        for s in self.sinks.iter_mut() {
+            if let Err(e) = s.event(ev, severity) {
+                error!("event sink failed on {} for {}: {:#}", ev.name(), ev.path().display(), e);
+            }
+        }
+    }
+
+    pub fn flush(&mut self) {
+        for s in self.sinks.iter_mut() {
+            if let Err(e) = s.flush() {
+                error!("event sink failed to flush: {:#}", e);
+            }
+        }
+    }
+}
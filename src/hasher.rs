@@ -0,0 +1,180 @@
+use std::fmt;
+use std::io::{self, Read};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+///
+/// Content hash algorithm used for the files in a state
+///
+/// sha1 is what state files have always held and stays the default.  Values of other
+/// algorithms carry a prefix like "sha256:" so a state never silently mixes them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgo {
+    Sha1,
+    Sha256,
+    Blake3,
+}
+
+impl FromStr for HashAlgo {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sha1" => Ok(HashAlgo::Sha1),
+            "sha256" => Ok(HashAlgo::Sha256),
+            "blake3" => Ok(HashAlgo::Blake3),
+            _ => Err(anyhow!("unknown hash \"{}\", expected sha1, sha256 or blake3", s)),
+        }
+    }
+}
+
+impl fmt::Display for HashAlgo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HashAlgo::Sha1 => "sha1",
+            HashAlgo::Sha256 => "sha256",
+            HashAlgo::Blake3 => "blake3",
+        })
+    }
+}
+
+#[derive(Clone)]
+enum Running {
+    Sha1(sha1::Sha1),
+    Sha256(sha2::Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+/// an in progress hash of one file
+#[derive(Clone)]
+pub struct Hasher(Running);
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match &mut self.0 {
+            Running::Sha1(m) => m.update(data),
+            Running::Sha256(m) => sha2::Digest::update(m, data),
+            Running::Blake3(m) => {
+                m.update(data);
+            }
+        }
+    }
+
+    pub fn finish(self) -> HashValue {
+        match self.0 {
+            Running::Sha1(m) => HashValue(m.digest().to_string()),
+            Running::Sha256(m) => HashValue(format!("sha256:{:x}", sha2::Digest::finalize(m))),
+            Running::Blake3(m) => HashValue(format!("blake3:{}", m.finalize().to_hex())),
+        }
+    }
+}
+
+impl HashAlgo {
+    pub const VARIANTS: &'static [&'static str] = &["sha1", "sha256", "blake3"];
+
+    pub fn hasher(&self) -> Hasher {
+        Hasher(match self {
+            HashAlgo::Sha1 => Running::Sha1(sha1::Sha1::new()),
+            HashAlgo::Sha256 => Running::Sha256(<sha2::Sha256 as sha2::Digest>::new()),
+            HashAlgo::Blake3 => Running::Blake3(Box::new(blake3::Hasher::new())),
+        })
+    }
+
+    /// hash everything `reader` gives using `buf` for reads, returns the hash and bytes read
+    pub fn digest_reader<R: Read>(&self, reader: R, buf: &mut [u8]) -> Result<(HashValue, usize)> {
+        let (digest, size, _) = self.digest_reader_marked(reader, buf, None)?;
+        Ok((digest, size))
+    }
+
+    /// as `digest_reader`, also giving the hash of the first `mark` bytes when there are that many
+    pub fn digest_reader_marked<R: Read>(&self, mut reader: R, buf: &mut [u8], mark: Option<u64>)
+        -> std::result::Result<(HashValue, usize, Option<HashValue>), ReadError> {
+        let mut m = self.hasher();
+        let mut size = 0;
+        let mut prefix = None;
+        loop {
+            if mark == Some(size as u64) {
+                prefix = Some(m.clone().finish());
+            }
+            let count = match reader.read(buf) {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(ReadError { offset: size as u64, source: e }),
+            };
+            if count == 0 {
+                break;
+            }
+            let mut chunk = &buf[..count];
+            // split the read at the mark so the hash can be taken right there
+            if let Some(mk) = mark.filter(|mk| (size as u64) < *mk && *mk < (size + count) as u64) {
+                let (head, tail) = chunk.split_at((mk - size as u64) as usize);
+                m.update(head);
+                prefix = Some(m.clone().finish());
+                chunk = tail;
+            }
+            m.update(chunk);
+            size += count;
+        }
+        Ok((m.finish(), size, prefix))
+    }
+}
+
+/// a read that failed part way into a file, e.g. EIO from a bad sector
+#[derive(Debug)]
+pub struct ReadError {
+    pub offset: u64,
+    pub source: io::Error,
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "read failed at byte {}: {}", self.offset, self.source)
+    }
+}
+
+impl std::error::Error for ReadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+///
+/// A content hash as kept in the state - hex, prefixed with the algorithm unless sha1
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct HashValue(String);
+
+impl HashValue {
+    pub fn algo(&self) -> HashAlgo {
+        match self.0.split_once(':') {
+            Some(("sha256", _)) => HashAlgo::Sha256,
+            Some(("blake3", _)) => HashAlgo::Blake3,
+            _ => HashAlgo::Sha1,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for HashValue {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let hex = s.split_once(':').map_or(s, |(_, h)| h);
+        if hex.is_empty() || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(anyhow!("unable to convert string to a hash value: \"{}\"", s));
+        }
+        Ok(HashValue(s.to_string()))
+    }
+}
+
+impl fmt::Display for HashValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
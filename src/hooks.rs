@@ -0,0 +1,133 @@
+use std::io::Write;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread::{spawn, JoinHandle};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use crossbeam_channel::Sender;
+use log::{debug, error};
+
+use crate::events::{event_json, Event, EventSink};
+use crate::policy::Severity;
+use crate::sinks::event_command;
+
+///
+/// User commands run on changes and at the end of a run
+///
+/// `on_change` runs once per event with the SHAFILES_* variables set, up to `max_running` at a
+/// time and off the state recording thread.  `on_complete` runs once after the state is saved
+/// with the run summary as JSON on stdin.  Either is killed if it runs past `timeout`.
+#[derive(Debug, Clone)]
+pub struct Hooks {
+    pub on_change: Option<String>,
+    pub on_complete: Option<String>,
+    pub max_running: usize,
+    pub timeout: Duration,
+}
+
+impl Default for Hooks {
+    fn default() -> Self {
+        Hooks { on_change: None, on_complete: None, max_running: 4, timeout: Duration::from_secs(60) }
+    }
+}
+
+/// wait for a child, killing it once `timeout` has passed
+fn wait_timeout(child: &mut Child, timeout: Duration) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            if !status.success() {
+                bail!("exited with {}", status);
+            }
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            bail!("killed after {} secs timeout", timeout.as_secs());
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// run `cmd` with `input` on stdin and wait up to `timeout` for it
+pub fn run_with_timeout(mut cmd: Command, input: &[u8], timeout: Duration) -> Result<()> {
+    let mut child = cmd.stdin(Stdio::piped()).spawn().context("cannot start")?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // written aside so a command that never reads stdin is still timed out, the write
+        // failing once it exits - and a command that does not read stdin at all is fine
+        let input = input.to_vec();
+        spawn(move || stdin.write_all(&input));
+    }
+    wait_timeout(&mut child, timeout)
+}
+
+/// run the on complete command, if any, with the summary JSON
+pub fn run_on_complete(hooks: &Hooks, summary_json: &str) -> Result<()> {
+    if let Some(cmd) = &hooks.on_complete {
+        let mut c = Command::new("sh");
+        c.arg("-c").arg(cmd);
+        run_with_timeout(c, summary_json.as_bytes(), hooks.timeout)
+            .with_context(|| format!("on complete hook \"{}\"", cmd))?;
+    }
+    Ok(())
+}
+
+///
+/// Hands events to a small pool of threads running the on change command or an exec sink's
+///
+/// The queue is bounded so a slow hook slows the scan down rather than piling up events.
+/// Failures are collected in `failures` for the run summary.
+pub struct HookSink {
+    send: Option<Sender<(Event, Severity)>>,
+    workers: Vec<JoinHandle<()>>,
+    pub failures: Arc<Mutex<Vec<String>>>,
+}
+
+impl HookSink {
+    pub fn new(cmd: &str, hooks: &Hooks) -> Self {
+        let (send, recv) = crossbeam_channel::bounded::<(Event, Severity)>(hooks.max_running * 4);
+        let failures = Arc::new(Mutex::new(vec![]));
+        let workers = (0..hooks.max_running.max(1)).map(|_| {
+            let recv = recv.clone();
+            let failures = failures.clone();
+            let cmd = cmd.to_string();
+            let timeout = hooks.timeout;
+            spawn(move || {
+                for (ev, severity) in recv.iter() {
+                    let input = serde_json::to_vec(&event_json(&ev, severity)).unwrap_or_default();
+                    debug!("hook \"{}\" for {} {}", cmd, ev.name(), ev.path().display());
+                    if let Err(e) = run_with_timeout(event_command(&cmd, &ev, severity), &input, timeout) {
+                        let msg = format!("hook \"{}\" for {} {}: {:#}", cmd, ev.name(), ev.path().display(), e);
+                        error!("{}", msg);
+                        failures.lock().unwrap().push(msg);
+                    }
+                }
+            })
+        }).collect();
+        HookSink { send: Some(send), workers, failures }
+    }
+}
+
+impl EventSink for HookSink {
+    fn event(&mut self, ev: &Event, severity: Severity) -> Result<()> {
+        match &self.send {
+            Some(s) => s.send((ev.clone(), severity)).map_err(|_| anyhow!("on change hook workers are gone")),
+            None => bail!("on change hooks already finished"),
+        }
+    }
+
+    /// waits for every queued hook to finish
+    fn flush(&mut self) -> Result<()> {
+        self.send = None;
+        for h in self.workers.drain(..) {
+            h.join().map_err(|_| anyhow!("on change hook worker panicked"))?;
+        }
+        Ok(())
+    }
+
+    fn failures(&self) -> Option<Arc<Mutex<Vec<String>>>> {
+        Some(self.failures.clone())
+    }
+}
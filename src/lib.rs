@@ -0,0 +1,33 @@
+//!
+//! Scans files for changes and tracks them against the state of prior runs
+//!
+//! The `scanner::Scanner` builder runs the whole pipeline - directory walking threads feed
+//! per device pools of hashing threads which feed a single thread recording into a `ShaSet`.
+//! The `shafiles` binary is a thin command line front end over it.
+
+pub mod baseline;
+pub mod config;
+pub mod daemon;
+pub mod device_pool;
+pub mod dupes;
+pub mod events;
+pub mod hasher;
+pub mod hooks;
+pub mod lock_file;
+pub mod mem_budget;
+pub mod policy;
+pub mod progress;
+pub mod read_order;
+pub mod scanner;
+pub mod sha_state;
+pub mod sinks;
+pub mod state_backend;
+pub mod state_crypt;
+pub mod state_seal;
+pub mod tuning;
+pub mod watch;
+pub mod worker_queue;
+
+pub use events::{Event, EventSink};
+pub use scanner::{ScanSummary, Scanner};
+pub use watch::Watcher;
@@ -0,0 +1,73 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use log::info;
+
+///
+/// An advisory `flock` held until dropped, with the holder's PID written in the file
+///
+/// The file itself is left behind on release - removing it would let a waiting run lock a
+/// file that a third run has already replaced.
+pub struct LockFile {
+    file: File,
+    path: PathBuf,
+}
+
+impl LockFile {
+    /// take the lock on `path`, waiting up to `wait` for another holder or failing at once
+    pub fn acquire(path: &Path, wait: Option<Duration>) -> Result<Self> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)
+            .with_context(|| format!("cannot open lock file \"{}\"", path.display()))?;
+        let start = Instant::now();
+        let mut told = false;
+        while unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let e = std::io::Error::last_os_error();
+            if e.raw_os_error() != Some(libc::EWOULDBLOCK) {
+                return Err(e).with_context(|| format!("cannot lock \"{}\"", path.display()));
+            }
+            let holder = holder(&mut file);
+            match wait {
+                None => bail!("\"{}\" is locked by {} - another run is using this state, use --wait-lock to wait for it",
+                    path.display(), holder),
+                Some(w) if start.elapsed() >= w => bail!("\"{}\" is still locked by {} after waiting {} secs",
+                    path.display(), holder, w.as_secs()),
+                Some(_) => {
+                    if !told {
+                        info!("waiting for lock \"{}\" held by {}", path.display(), holder);
+                        told = true;
+                    }
+                    sleep(Duration::from_millis(100));
+                }
+            }
+        }
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(LockFile { file, path: path.to_path_buf() })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_UN) };
+    }
+}
+
+/// who holds the lock, going by the PID they wrote
+fn holder(file: &mut File) -> String {
+    let mut s = String::new();
+    let _ = file.seek(SeekFrom::Start(0)).and_then(|_| file.read_to_string(&mut s));
+    match s.trim().parse::<u32>() {
+        Ok(pid) => format!("pid {}", pid),
+        Err(_) => "an unknown process".to_string(),
+    }
+}
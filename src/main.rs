@@ -2,277 +2,167 @@
 #![allow(unused_imports)]
 #![allow(unused_variables)]
 
-mod sha_state;
-
-use std::thread::spawn;
-use std::path::{PathBuf, Path};
-use crossbeam_channel::{Sender, Receiver};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 use anyhow::{anyhow, Context, Result};
 use log::{debug, error, info, trace, warn};
-use std::fs::{symlink_metadata, FileType};
-use sha1::{Sha1, Digest};
-use std::fs;
 
 mod cli;
-mod worker_queue;
 
-use worker_queue::WorkerQueue;
-use std::io::Read;
-use std::time::{Duration, Instant};
-use crate::sha_state::{ShaState, ShaSet, DiffResult};
-use std::sync::{Arc, RwLock, Mutex};
-use crate::cli::Cli;
-use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use cli::{Command, CtlCommand, CtlOpts, WatchOpts};
 
-pub struct Stats {
-    pub fc: AtomicUsize,
-    pub bc: AtomicUsize,
-}
+use shafiles::baseline::Selection;
+use shafiles::config::{Config, DaemonSettings};
+use shafiles::daemon::{self, Daemon, Request};
+use shafiles::dupes;
+use shafiles::Event;
 
-use lazy_static::lazy_static;
-
-lazy_static! {
-    pub static ref stats: Stats = Stats{ fc: AtomicUsize::new(0), bc: AtomicUsize::new(0) };
-}
 
+/// exit status of a scan that found changes its policy rates as alerts, 1 being an error
+const EXIT_ALERTS: i32 = 2;
+/// exit status of a scan with --fail-on-error that could not scan some paths
+const EXIT_ERRORS: i32 = 3;
 
 fn main() {
-    if let Err(err) = sha_them_all() {
-        eprintln!("ERROR in main: {}, {:#?}",&err, &err);
-        std::process::exit(1);
-    }
-}
-
-fn read_dir_thread(queue: &mut WorkerQueue<Option<PathBuf>>, out_q: &mut Sender<Option<PathBuf>>) {
-    loop {
-        match _read_dir_thread(queue, out_q) {
-            Err(e) => {
-                error!("read_dir thread top: {}", e);
-            }
-            Ok(()) => break,
+    match sha_them_all() {
+        Err(err) => {
+            eprintln!("ERROR in main: {}, {:#?}",&err, &err);
+            std::process::exit(1);
         }
+        Ok(code) => std::process::exit(code),
     }
 }
 
-fn _read_dir_thread(queue: &mut WorkerQueue<Option<PathBuf>>, send: &mut Sender<Option<PathBuf>>) -> Result<()> {
-    loop {
-        match queue.pop() {
-            None => return Ok(()),
-            Some(path) => {
-                trace!("scanning dir {}", path.display());
-                let dir_itr = match std::fs::read_dir(&path) {
-                    Err(e) => {
-                        error!("stat of dir: '{}', error: {}", path.display(), e);
-                        continue;
-                    }
-                    Ok(rd) => rd,
-                };
-                for entry in dir_itr {
-                    let entry = entry?;
-                    let path = entry.path();
-                    let md = match symlink_metadata(&entry.path()) {
-                        Err(e) => {
-                            error!("stat of file for symlink: '{}', error: {}", path.display(), e);
-                            continue;
-                        }
-                        Ok(md) => md,
-                    };
+fn sha_them_all() -> Result<i32> {
+
+    let cli = crate::cli::get_cli();
 
-                    let file_type: FileType = md.file_type();
-                    if !file_type.is_symlink() {
-                        if file_type.is_file() {
-                            trace!("sending file {}", path.display());
-                            send.send(Some(path))?;
-                        } else if file_type.is_dir() {
-                            queue.push(Some(path))?;
-                        }
-                    }
+    stderrlog::new()
+        .module(module_path!())
+        .quiet(false)
+        .verbosity(cli.verbosity)
+        .timestamp(stderrlog::Timestamp::Millisecond) // opt.ts.unwrap_or(stderrlog::Timestamp::Off))
+        .init()
+        .unwrap();
+
+    match &cli.cmd {
+        Command::Scan(opts) => {
+            let profile = opts.resolve(&cli.config)?;
+            let summary = profile.scanner()?.run()?;
+            if summary.roots.len() > 1 {
+                for r in &summary.roots {
+                    info!("{}: {} files {} bytes: {} added, {} changed, {} unchanged, {} deleted, {} moved, {} errors",
+                        r.root.display(), r.files, r.bytes, r.added, r.changed, r.unchanged, r.deleted, r.moved, r.errors);
                 }
             }
-        }
-    }
-}
-
-fn sha_files(recv: &Receiver<Option<PathBuf>>, send: &Sender<Option<ShaState>>) -> usize {
-    let mut size = 0;
-    loop {
-        match _sha_files(recv, send) {
-            Err(e) => {
-                error!("sha_file thread top: {}", e);
+            info!("{} files {} bytes in {:.3} secs: {} added, {} changed, {} unchanged, {} deleted, {} moved, {} errors",
+                summary.files, summary.bytes, summary.secs, summary.added, summary.changed(), summary.unchanged,
+                summary.deleted, summary.moved, summary.errors);
+            if !summary.hook_failures.is_empty() {
+                warn!("{} hooks failed", summary.hook_failures.len());
+            }
+            if summary.errors > 0 {
+                let kinds: Vec<String> = summary.errors_by_kind.iter().map(|(k, n)| format!("{} {}", n, k.name())).collect();
+                warn!("{} paths could not be scanned: {}", summary.errors, kinds.join(", "));
+            }
+            if summary.unstable > 0 {
+                warn!("{} files were changing as they were read and kept their prior entries", summary.unstable);
+            }
+            if !summary.corrupted.is_empty() {
+                error!("{} files corrupted - changed on disk with their mtime and size unchanged:", summary.corrupted.len());
+                for p in &summary.corrupted {
+                    error!("    {}", p.display());
+                }
+            }
+            if summary.alerts > 0 {
+                error!("{} changes rated as alerts", summary.alerts);
+                return Ok(EXIT_ALERTS);
+            }
+            if summary.errors > 0 && profile.fail_on_error.unwrap_or(false) {
+                return Ok(EXIT_ERRORS);
             }
-            Ok(s) => {
-                size += s;
-                break
-            },
         }
-    }
-    size
-}
-
-fn _sha_files(recv: &Receiver<Option<PathBuf>>, send: &Sender<Option<ShaState>>) -> Result<usize> {
-    let mut buf = vec![0u8; 64 * 1024 * 1024];
-    let mut size = 0;
-    loop {
-        trace!("waiting...");
-        match recv.recv()? {
-            None => return Ok(size), // this is the end my friend
-            Some(path) => {
-                match sha_a_file(&path, &mut buf) {
-                    Err(e) => {
-                        error!("sha1 on file {} failed, {}", &path.display(), e);
-                        ()
-                    }
-                    Ok( (state,sz)) => {
-                        size += sz;
-                        stats.bc.fetch_add(sz, Ordering::Relaxed);
-                        stats.fc.fetch_add(1, Ordering::Relaxed);
-                        send.send(Some(state))?;
-                        ()
-                    }
+        Command::Watch(w) => watch(w, &cli.config)?,
+        Command::Daemon => {
+            stop_on_signals();
+            Daemon::new(Config::load(&cli.config)?).run(&STOP)?
+        }
+        Command::Ctl(c) => ctl(c, &cli.config)?,
+        Command::Accept(a) => {
+            let profile = a.scan.resolve(&cli.config)?;
+            let selection = Selection::new(&a.paths, a.events.clone())?;
+            let taken = profile.state()?.accept(&selection)?;
+            for ev in &taken {
+                match ev {
+                    Event::Moved { from, to, .. } => info!("accepted moved {} -> {}", from.display(), to.display()),
+                    ev => info!("accepted {} {}", ev.name(), ev.path().display()),
                 }
             }
-        };
+            info!("{} changes accepted into the baseline", taken.len());
+        }
+        Command::Rekey(r) => {
+            let profile = r.resolve(&cli.config)?;
+            let keys = r.renewed(&profile).state_keys()?;
+            let encrypted = keys.encryption().is_some();
+            profile.state()?.rekey(keys)?;
+            info!("state {} is now {}", profile.state_path()?.display(), if encrypted { "encrypted with the new secret" } else { "decrypted" });
+        }
+        Command::Dupes(d) => {
+            let set = d.resolve(&cli.config)?.state()?.read()?;
+            let mut groups = dupes::find(&set, d.min_size.0 as u64);
+            if d.confirm {
+                groups = dupes::confirm(groups);
+            }
+            dupes::write(&groups, d.format, &mut std::io::stdout().lock())?;
+            info!("{} groups of duplicates, {} bytes wasted", groups.len(), groups.iter().map(|g| g.wasted).sum::<u64>());
+        }
     }
-}
 
-fn sha_a_file(path: &Path, buf: &mut Vec<u8>) -> Result<(ShaState, usize)> {
-    let mut file = fs::File::open(&path).context("open failed")?;
-    let (hash,size) = sha1_digest(&mut file, buf).context("digest_reader failed")?;
-    trace!("path: \"{}\" sha1: {}", path.display(), &hash.to_string());
-    let mtime = path.metadata()?.modified()?;
-    Ok((ShaState::new(path.to_path_buf(), hash, mtime), size))
+    Ok(0)
 }
 
-fn sha1_digest<R: Read>(mut reader: R, buf: &mut Vec<u8>) -> Result<(Digest, usize)> {
-    let mut m = sha1::Sha1::new();
-    let mut size = 0;
-    loop {
-        let count = reader.read(&mut buf[..])?;
-        size += count;
-        if count == 0 {
-            break;
-        }
-        m.update(&buf[..count]);
-    }
+static STOP: AtomicBool = AtomicBool::new(false);
 
-    Ok((m.digest(), size))
+extern "C" fn on_stop_signal(_: libc::c_int) {
+    STOP.store(true, Ordering::Relaxed);
 }
 
-fn record_state(cli: &Arc<Cli>, recv: Receiver<Option<ShaState>>, state: &mut Arc<Mutex<ShaSet>>) {
-    loop {
-        match recv.recv() {
-            Err(e) => panic!("write thread errored during receive: {}", e),
-            Ok(None) => return,
-            Ok(Some(state_entry)) => {
-                match state.lock() {
-                    Err(e) => panic!("write thread error locking state {}", e),
-                    Ok(mut state) => {
-                        let info = state_entry.to_string();
-                        match state.add(state_entry) {
-                            Err(e) => error!("Cannot add entry for {} due to {}", info, e),
-                            Ok(diff) => {
-                                match diff {
-                                    DiffResult::BothDiff => warn!("SHA TIME CHANGE: {}", info),
-                                    DiffResult::ShaDiff => warn!("SHA CHANGE: {}", info),
-                                    DiffResult::TimeDiff => warn!("TIME CHANGE: {}", info),
-                                    _ => (),
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-        }
+fn stop_on_signals() {
+    unsafe {
+        libc::signal(libc::SIGINT, on_stop_signal as *const () as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, on_stop_signal as *const () as libc::sighandler_t);
     }
 }
 
-fn ticker() {
-    let mut bc = 0;
-    let mut fc = 0;
-    loop {
-        std::thread::sleep(Duration::from_secs(1));
-        bc = stats.bc.load(Ordering::Relaxed);
-        fc = stats.fc.load(Ordering::Relaxed);
-        info!("TICK  {} files  {} GB", fc, bc/(1024*1024*1024));
+fn watch(w: &WatchOpts, config: &Path) -> Result<()> {
+    let profile = w.scan.resolve(config)?;
+    let watcher = profile.watcher()?;
+    if w.initial_scan {
+        profile.scanner()?.run()?;
     }
+    stop_on_signals();
+
+    let watcher = watcher
+        .settle(Duration::from_millis(w.settle_ms))
+        .save_every(Duration::from_secs(w.save_secs))
+        .rescan_every(Duration::from_secs(w.rescan_secs))
+        .fanotify(w.fanotify);
+    watcher.rescan(move || profile.scanner()?.run()).run(&STOP)
 }
 
-fn sha_them_all() -> Result<()> {
-
-    let cli = Arc::new(crate::cli::get_cli());
-
-    stderrlog::new()
-        .module(module_path!())
-        .quiet(false)
-        .verbosity(cli.verbosity)
-        .timestamp(stderrlog::Timestamp::Millisecond) // opt.ts.unwrap_or(stderrlog::Timestamp::Off))
-        .init()
-        .unwrap();
-
-    let h_ticker = spawn(move || ticker());
-
-    let mut dir_q: WorkerQueue<Option<PathBuf>> = WorkerQueue::new(cli.threads_dir, 0);
-    let (send, recv) = crossbeam_channel::unbounded();
-    let (send_state, recv_state) = crossbeam_channel::unbounded();
-
-    let mut state = Arc::new(Mutex::new(ShaSet::new(&cli.state_path)?));
-
-    let start = Instant::now();
-
-    let h_state_write = {
-        let cli_c = cli.clone();
-        let mut state_c = state.clone();
-        spawn(move || record_state(&cli_c, recv_state, &mut state_c))
+fn ctl(c: &CtlOpts, config: &Path) -> Result<()> {
+    let socket = match &c.socket {
+        Some(s) => s.clone(),
+        None if config.exists() => Config::load(config)?.daemon.socket,
+        None => DaemonSettings::default().socket,
     };
-
-    let mut h_dir_threads = vec![];
-    for _i in 0..cli.threads_dir {
-        let mut dir_q = dir_q.clone();
-        let mut send = send.clone();
-        let h = spawn(move || read_dir_thread(&mut dir_q, &mut send));
-        h_dir_threads.push(h);
-    }
-
-    let mut h_sha_threads = vec![];
-    for _i in 0..cli.threads_sha {
-        let mut recv = recv.clone();
-        let mut send_state = send_state.clone();
-        let h = spawn(move || sha_files(&mut recv, &mut send_state));
-        h_sha_threads.push(h);
-    }
-
-    // prime the read dir pump
-    dir_q.push(Some(cli.top_dir.clone()));
-
-
-    // wait on work as boss queue - then stop them
-    loop {
-        let x = dir_q.wait_for_finish_timeout(Duration::from_millis(250))?;
-        if x != -1 { break; }
-    }
-    for _ in 0..cli.threads_dir { dir_q.push(None)?; }
-    for h in h_dir_threads {
-        h.join().unwrap();
-    }
-    info!("directory scanning is done");
-
-    // wait on sha threads
-    for _ in 0..cli.threads_sha { send.send(None)?; }
-    let mut tot_bytes = 0;
-    for h in h_sha_threads {
-        tot_bytes += h.join().unwrap();
-    }
-    let secs = start.elapsed().as_secs_f64();
-    let rate = (tot_bytes as f64 / secs)/(1024.0*1024.0);
-    info!("sha of files is done in {:.3} secs {} total  {:.2}MB/ sec", secs, tot_bytes, rate);
-
-    send_state.send(None)?;
-    h_state_write.join().unwrap();
-
-    match state.lock() { // this match is needed I think because LockGuard points to special version of Result
-        Err(e) => panic!("cannot lock state at the to write the current entries"),
-        Ok(mut s) => s.write_entries(&cli.state_path)?,
-    }
-
+    let req = match &c.req {
+        CtlCommand::Status => Request::Status,
+        CtlCommand::Scan { profile } => Request::Scan { profile: profile.clone() },
+        CtlCommand::Summary { profile } => Request::Summary { profile: profile.clone() },
+    };
+    let answer = daemon::request(&socket, &req)?;
+    println!("{}", serde_json::to_string_pretty(&answer)?);
     Ok(())
 }
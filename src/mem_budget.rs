@@ -0,0 +1,182 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Result};
+use log::{info, warn};
+
+/// rough cost of a path sitting in the file queue
+const QUEUED_PATH_BYTES: usize = 256;
+/// rough cost of a hashed entry waiting in the state queue
+const QUEUED_STATE_BYTES: usize = 512;
+/// buffers are never shrunk below this to fit a budget
+const MIN_BUFFER: usize = 64 * 1024;
+
+///
+/// A byte count given on the command line like 512K, 64M or 2G
+///
+/// Suffixes are powers of 1024 and are case insensitive.  A plain number is bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteSize(pub usize);
+
+impl FromStr for ByteSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let (num, mult) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+            Some('K') => (&s[..s.len() - 1], 1024),
+            Some('M') => (&s[..s.len() - 1], 1024 * 1024),
+            Some('G') => (&s[..s.len() - 1], 1024 * 1024 * 1024),
+            _ => (s, 1),
+        };
+        let num: usize = num.parse().with_context(|| format!("cannot parse size \"{}\"", s))?;
+        num.checked_mul(mult).map(ByteSize).ok_or_else(|| anyhow!("size \"{}\" is too large", s))
+    }
+}
+
+///
+/// Thread counts, buffer and queue sizes to run with
+///
+/// These start out as given on the command line and are only changed by `fit` when a memory
+/// budget is set.
+#[derive(Debug, Clone)]
+pub struct MemPlan {
+    pub sha_threads: usize,
+    pub buffer_size: usize,
+    pub file_queue: usize,
+    pub state_queue: usize,
+}
+
+impl MemPlan {
+    /// estimated peak memory for this plan with `pools` sha thread pools, which share the file queue
+    pub fn estimate(&self, pools: usize) -> usize {
+        self.sha_threads * pools * self.buffer_size
+            + self.file_queue * QUEUED_PATH_BYTES
+            + self.state_queue * QUEUED_STATE_BYTES
+    }
+
+    ///
+    /// Shrink the plan until it fits in `budget` bytes
+    ///
+    /// Unbounded queues are bounded first, then buffers are halved down to a floor, then
+    /// sha threads are dropped one at a time down to 1.
+    pub fn fit(&mut self, budget: usize, pools: usize) -> Result<()> {
+        let orig = self.clone();
+        let pools = pools.max(1);
+        let queue_share = budget / 8;
+        if self.file_queue == 0 || self.file_queue * QUEUED_PATH_BYTES > queue_share / 2 {
+            self.file_queue = (queue_share / 2 / QUEUED_PATH_BYTES).max(1);
+        }
+        if self.state_queue == 0 || self.state_queue * QUEUED_STATE_BYTES > queue_share / 2 {
+            self.state_queue = (queue_share / 2 / QUEUED_STATE_BYTES).max(1);
+        }
+        while self.estimate(pools) > budget && self.buffer_size / 2 >= MIN_BUFFER {
+            self.buffer_size /= 2;
+        }
+        while self.estimate(pools) > budget && self.sha_threads > 1 {
+            self.sha_threads -= 1;
+        }
+        if self.estimate(pools) > budget {
+            return Err(anyhow!("--max-memory of {} bytes is too small, the smallest plan needs about {} bytes",
+                budget, self.estimate(pools)));
+        }
+        if self.sha_threads != orig.sha_threads || self.buffer_size != orig.buffer_size {
+            warn!("memory budget of {} bytes: sha threads {} -> {}, buffer size {} -> {}",
+                budget, orig.sha_threads, self.sha_threads, orig.buffer_size, self.buffer_size);
+        }
+        info!("memory plan: {} sha threads x {} pools, {} byte buffers, file queue {}, state queue {}, about {} bytes",
+            self.sha_threads, pools, self.buffer_size, self.file_queue, self.state_queue, self.estimate(pools));
+        Ok(())
+    }
+}
+
+///
+/// Room for `cap` items across several queues, unbounded when cap is 0
+///
+/// A bounded channel per queue would make whoever feeds them wait on the fullest one, this only
+/// waits when all of them together are full.
+#[derive(Clone)]
+pub struct QueueBudget {
+    slots: Option<(crossbeam_channel::Sender<()>, crossbeam_channel::Receiver<()>)>,
+}
+
+impl QueueBudget {
+    pub fn new(cap: usize) -> Self {
+        QueueBudget { slots: (cap > 0).then(|| crossbeam_channel::bounded(cap)) }
+    }
+
+    /// wait for room for one more item
+    pub fn take(&self) {
+        if let Some((send, _)) = &self.slots {
+            // both ends are held here, so this cannot fail
+            let _ = send.send(());
+        }
+    }
+
+    /// an item left its queue
+    pub fn give_back(&self) {
+        if let Some((_, recv)) = &self.slots {
+            let _ = recv.try_recv();
+        }
+    }
+}
+
+/// a channel that is unbounded when cap is 0
+pub fn channel<T>(cap: usize) -> (crossbeam_channel::Sender<T>, crossbeam_channel::Receiver<T>) {
+    if cap == 0 {
+        crossbeam_channel::unbounded()
+    } else {
+        crossbeam_channel::bounded(cap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_sizes() {
+        let parse = |s: &str| s.parse::<ByteSize>().map(|b| b.0);
+        assert_eq!(parse("4096").unwrap(), 4096);
+        assert_eq!(parse("512K").unwrap(), 512 * 1024);
+        assert_eq!(parse("64m").unwrap(), 64 * 1024 * 1024);
+        assert_eq!(parse(" 2G ").unwrap(), 2 * 1024 * 1024 * 1024);
+        assert_eq!(parse("0").unwrap(), 0);
+        for bad in ["", "M", "1.5G", "-1K", "12T", "64 M", "99999999999999999999G"] {
+            assert!(parse(bad).is_err(), "{:?} parsed", bad);
+        }
+    }
+
+    #[test]
+    fn fit_shrinks_buffers_before_threads() {
+        let mut plan = MemPlan { sha_threads: 4, buffer_size: 64 * 1024 * 1024, file_queue: 0, state_queue: 0 };
+        plan.fit(64 * 1024 * 1024, 2).unwrap();
+        assert_eq!(plan.sha_threads, 4);
+        assert!(plan.estimate(2) <= 64 * 1024 * 1024);
+        assert!(plan.file_queue > 0 && plan.state_queue > 0);
+
+        let mut plan = MemPlan { sha_threads: 4, buffer_size: 64 * 1024 * 1024, file_queue: 10, state_queue: 10 };
+        plan.fit(200 * 1024, 1).unwrap();
+        assert_eq!((plan.sha_threads, plan.buffer_size), (3, MIN_BUFFER));
+        assert!(MemPlan { sha_threads: 1, ..plan }.fit(1024, 1).is_err());
+    }
+
+    #[test]
+    fn queue_budget_waits_only_when_all_is_used() {
+        let budget = QueueBudget::new(2);
+        budget.take();
+        budget.take();
+        let waiting = {
+            let budget = budget.clone();
+            std::thread::spawn(move || budget.take())
+        };
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert!(!waiting.is_finished());
+        budget.give_back();
+        waiting.join().unwrap();
+
+        let unbounded = QueueBudget::new(0);
+        for _ in 0..100 {
+            unbounded.take();
+        }
+    }
+}
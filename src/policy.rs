@@ -0,0 +1,396 @@
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Result};
+use globset::GlobSet;
+use serde::{Deserialize, Serialize};
+
+use crate::events::Event;
+use crate::hasher::HashAlgo;
+use crate::scanner::build_globs;
+use crate::sha_state::{FileKind, ShaState};
+
+///
+/// How much a change matters
+///
+/// Ignored changes are not reported at all, alerts make the run exit with status 2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Ignore,
+    Info,
+    Alert,
+}
+
+impl Severity {
+    pub const VARIANTS: &'static [&'static str] = &["ignore", "info", "alert"];
+
+    pub fn name(&self) -> &'static str {
+        Severity::VARIANTS[*self as usize]
+    }
+}
+
+impl FromStr for Severity {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "ignore" => Ok(Severity::Ignore),
+            "info" => Ok(Severity::Info),
+            "alert" => Ok(Severity::Alert),
+            _ => Err(anyhow!("unknown severity \"{}\", expected one of {}", s, Severity::VARIANTS.join(", "))),
+        }
+    }
+}
+
+/// what about a file a rule looks at
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Attr {
+    Content,
+    Mtime,
+    Mode,
+    Owner,
+    Size,
+    /// the number of hard links to the file
+    Links,
+    /// the size may grow but not shrink, e.g. for logs
+    GrowOnly,
+    /// may only be added to, what was there before must be unchanged, e.g. for audit trails
+    AppendOnly,
+}
+
+/// what a rule checks when it does not say
+const DEFAULT_CHECK: &[Attr] = &[Attr::Content, Attr::Mtime, Attr::Mode, Attr::Owner, Attr::Size, Attr::Links];
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// globs matched against the full path
+    pub paths: Vec<String>,
+    pub check: Option<Vec<Attr>>,
+    pub severity: Severity,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicyFile {
+    #[serde(default)]
+    rule: Vec<Rule>,
+}
+
+///
+/// Rules saying which changes matter where and how much, the first rule matching a path wins
+///
+/// ```toml
+/// [[rule]]
+/// paths = ["/var/log/**"]
+/// check = ["grow_only"]
+/// severity = "info"
+///
+/// [[rule]]
+/// paths = ["/etc/**"]
+/// severity = "alert"
+///
+/// [[rule]]
+/// paths = ["**/*.tmp"]
+/// severity = "ignore"
+/// ```
+///
+/// `check` takes content, mtime, mode, owner, size, links, grow_only and append_only, and leaving it
+/// out checks everything but grow_only and append_only.  Paths no rule matches are checked for
+/// everything at info.  Additions, deletions and moves take the severity of their path, the
+/// higher of the two for a move.
+///
+/// For append_only the part of the file the last run saw is hashed on its own and compared to
+/// the hash kept then, so a change is told apart as appended, rewritten or truncated.  Appends
+/// are never more than info, it is rewriting history that takes the rule's severity.  A
+/// confirmed corruption is an alert wherever it is not ignored.
+///
+/// Directories, symlinks and special files have no content or telling mtime.  Their mode and
+/// owner are checked as for files, along with where a symlink points and a device node's
+/// numbers, and an entry that turns into another type is always reported.
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    rules: Vec<(GlobSet, Rule)>,
+}
+
+impl Policy {
+    pub fn new(rules: Vec<Rule>) -> Result<Self> {
+        let rules = rules.into_iter().map(|r| Ok((build_globs(&r.paths)?, r))).collect::<Result<_>>()?;
+        Ok(Policy { rules })
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("cannot read policy \"{}\"", path.display()))?;
+        let file: PolicyFile = toml::from_str(&text).with_context(|| format!("bad policy \"{}\"", path.display()))?;
+        Policy::new(file.rule).with_context(|| format!("bad policy \"{}\"", path.display()))
+    }
+
+    fn rule(&self, path: &Path) -> Option<&Rule> {
+        self.rules.iter().find(|(g, _)| g.is_match(path)).map(|(_, r)| r)
+    }
+
+    fn check(&self, path: &Path) -> &[Attr] {
+        self.rule(path).and_then(|r| r.check.as_deref()).unwrap_or(DEFAULT_CHECK)
+    }
+
+    pub fn severity(&self, path: &Path) -> Severity {
+        self.rule(path).map(|r| r.severity).unwrap_or(Severity::Info)
+    }
+
+    /// how much an event matters, ignoring nothing for errors
+    pub fn severity_of(&self, ev: &Event) -> Severity {
+        match ev {
+            Event::Moved { from, to, .. } => self.severity(from).max(self.severity(to)),
+            Event::Error { path, .. } => self.severity(path).max(Severity::Info),
+            Event::Appended { path, .. } | Event::Unstable { path, .. } => self.severity(path).min(Severity::Info),
+            Event::Corruption { path, .. } if self.severity(path) != Severity::Ignore => Severity::Alert,
+            ev => self.severity(ev.path()),
+        }
+    }
+
+    /// how many bytes of the file behind `old` to hash on their own, for append only files
+    pub fn mark(&self, old: &ShaState, hash: HashAlgo) -> Option<u64> {
+        // a prefix hash can only be compared with a full one of the same algorithm
+        match self.check(old.path()).contains(&Attr::AppendOnly) && old.sha().algo() == hash {
+            true => old.size(),
+            false => None,
+        }
+    }
+
+    /// the events for a file seen again, none when nothing its rule checks has changed
+    pub fn changes(&self, old: &ShaState, new: &ShaState) -> Vec<Event> {
+        let path = new.path().to_path_buf();
+        // e.g. a binary swapped for a symlink, reported whatever the rule checks
+        if old.kind() != new.kind() {
+            return vec![Event::TypeChanged { path, old_kind: old.kind(), new_kind: new.kind() }];
+        }
+        let check = self.check(new.path());
+        let has = |a| check.contains(&a);
+        let sha_diff = old.sha() != new.sha();
+        let mtime_diff = old.mtime() != new.mtime();
+        // entries from before sizes were kept have none, so only their content is compared
+        let sizes = old.size().zip(new.size());
+        let content = (has(Attr::Content) && sha_diff)
+            || (has(Attr::Size) && sizes.is_some_and(|(o, n)| o != n))
+            || (has(Attr::GrowOnly) && sizes.is_some_and(|(o, n)| n < o));
+        let mut evs = vec![];
+        let history = match sizes {
+            Some((old_size, new_size)) if has(Attr::AppendOnly) && (sha_diff || old_size != new_size) => {
+                let (old_sha, new_sha) = (old.sha().clone(), new.sha().clone());
+                match new.prefix() {
+                    _ if new_size < old_size => Some(Event::Truncated { path: path.clone(), old_sha, new_sha, old_size, new_size }),
+                    Some(p) if p == old.sha() => Some(Event::Appended { path: path.clone(), old_sha, new_sha, old_size, new_size }),
+                    Some(_) => Some(Event::Rewritten { path: path.clone(), old_sha, new_sha, old_size, new_size }),
+                    // nothing to go by, e.g. the hash algorithm changed
+                    None => None,
+                }
+            }
+            _ => None,
+        };
+        if new.kind() != FileKind::File {
+            // no content, and mtimes that say little - a directory's moves with every file added
+            let (old_target, new_target) = (target_of(old), target_of(new));
+            if old_target != new_target {
+                evs.push(Event::TargetChanged { path: path.clone(), old_target, new_target });
+            }
+        } else if let Some(ev) = history {
+            evs.push(ev);
+        } else if content {
+            evs.push(Event::ContentChanged { path: path.clone(), old_sha: old.sha().clone(), new_sha: new.sha().clone(),
+                mtime_changed: mtime_diff });
+        } else if has(Attr::Mtime) && mtime_diff {
+            evs.push(Event::MetadataChanged { path: path.clone(), sha: new.sha().clone(), old_mtime: old.mtime(),
+                new_mtime: new.mtime() });
+        }
+        // entries from before modes and owners were kept have neither
+        let mode = has(Attr::Mode) && matches!((old.mode(), new.mode()), (Some(a), Some(b)) if a != b);
+        let owner = has(Attr::Owner) && matches!((old.owner(), new.owner()), (Some(a), Some(b)) if a != b);
+        if mode || owner {
+            evs.push(Event::PermsChanged { path: path.clone(), old_mode: old.mode(), new_mode: new.mode(),
+                old_owner: old.owner(), new_owner: new.owner() });
+        }
+        if let (true, Some(old_links), Some(new_links)) = (has(Attr::Links), old.nlink(), new.nlink()) {
+            if old_links != new_links {
+                evs.push(Event::LinksChanged { path, old_links, new_links });
+            }
+        }
+        evs
+    }
+}
+
+/// where a symlink points or a device node's major:minor, empty for anything else
+fn target_of(e: &ShaState) -> String {
+    match (e.target(), e.rdev()) {
+        (Some(t), _) => t.to_string_lossy().into_owned(),
+        (None, Some(dev)) => format!("{}:{}", libc::major(dev), libc::minor(dev)),
+        (None, None) => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::sha_state::testing::{at, file_mode, stat, MTIME, SHA_A, SHA_B, SHA_C};
+    use crate::sha_state::ShaSet;
+    use crate::state_seal::StateKeys;
+
+    /// a state as the first versions wrote it, a flat array without sizes, modes or roots
+    fn legacy_state() -> String {
+        format!(r#"[{{"path": "/var/log/audit.log", "sha": "{}",
+                      "mtime": {{"secs_since_epoch": {}, "nanos_since_epoch": 0}}, "t_deltas": 2, "sha_deltas": 1}}]"#,
+                SHA_A, MTIME)
+    }
+
+    /// a file as a scan records it
+    fn file(path: &str, sha: &str, secs: u64, size: u64, mode: u32) -> ShaState {
+        ShaState::new(PathBuf::from(path), sha.parse().unwrap(), at(secs), size).with_meta(&file_mode(mode))
+    }
+
+    /// /etc/passwd as the prior scan saw it
+    fn passwd() -> ShaState {
+        file("/etc/passwd", SHA_A, MTIME, 100, 0o644)
+    }
+
+    fn rule(paths: &[&str], check: Option<Vec<Attr>>, severity: Severity) -> Rule {
+        Rule { paths: paths.iter().map(|p| p.to_string()).collect(), check, severity }
+    }
+
+    /// /etc/passwd made a symlink to `target`
+    fn link(target: &str) -> ShaState {
+        let md = stat(|p| std::os::unix::fs::symlink(target, p).unwrap());
+        ShaState::special(PathBuf::from("/etc/passwd"), &md, Some(PathBuf::from(target)))
+    }
+
+    fn append_only() -> Policy {
+        Policy::new(vec![rule(&["/var/log/**"], Some(vec![Attr::AppendOnly]), Severity::Alert)]).unwrap()
+    }
+
+    #[test]
+    fn default_policy_reports_each_change() {
+        let p = Policy::default();
+        let changes = |sha, secs, size, mode| p.changes(&passwd(), &file("/etc/passwd", sha, secs, size, mode));
+        assert!(changes(SHA_A, MTIME, 100, 0o644).is_empty());
+        assert!(matches!(changes(SHA_B, MTIME + 1, 100, 0o644)[..], [Event::ContentChanged { mtime_changed: true, .. }]));
+        assert!(matches!(changes(SHA_A, MTIME + 1, 100, 0o644)[..], [Event::MetadataChanged { .. }]));
+        assert!(matches!(changes(SHA_A, MTIME, 101, 0o644)[..], [Event::ContentChanged { mtime_changed: false, .. }]));
+        assert!(matches!(changes(SHA_A, MTIME, 100, 0o600)[..],
+            [Event::PermsChanged { old_mode: Some(0o644), new_mode: Some(0o600), .. }]));
+        assert!(matches!(changes(SHA_B, MTIME, 100, 0o4755)[..], [Event::ContentChanged { .. }, Event::PermsChanged { .. }]));
+    }
+
+    #[test]
+    fn rules_check_only_what_they_list() {
+        let p = Policy::new(vec![rule(&["/etc/**"], Some(vec![Attr::Content]), Severity::Alert)]).unwrap();
+        assert!(p.changes(&passwd(), &file("/etc/passwd", SHA_A, MTIME + 1, 100, 0o600)).is_empty());
+        assert!(matches!(p.changes(&passwd(), &file("/etc/passwd", SHA_B, MTIME, 100, 0o644))[..],
+            [Event::ContentChanged { .. }]));
+        // paths no rule matches are checked for everything
+        let old = file("/srv/x", SHA_A, MTIME, 100, 0o644);
+        assert_eq!(p.changes(&old, &file("/srv/x", SHA_A, MTIME, 100, 0o600)).len(), 1);
+    }
+
+    #[test]
+    fn first_matching_rule_sets_the_severity() {
+        let p = Policy::new(vec![
+            rule(&["**/*.swp"], None, Severity::Ignore),
+            rule(&["/etc/**"], None, Severity::Alert),
+        ]).unwrap();
+        assert_eq!(p.severity(Path::new("/etc/.passwd.swp")), Severity::Ignore);
+        assert_eq!(p.severity(Path::new("/etc/passwd")), Severity::Alert);
+        assert_eq!(p.severity(Path::new("/srv/x")), Severity::Info);
+
+        let moved = Event::Moved { from: "/srv/x".into(), to: "/etc/x".into(), sha: SHA_A.parse().unwrap() };
+        assert_eq!(p.severity_of(&moved), Severity::Alert);
+        let error = Event::Error { path: "/etc/.passwd.swp".into(), kind: crate::events::ErrorKind::Read, error: String::new(),
+                                   offset: None };
+        assert_eq!(p.severity_of(&error), Severity::Info);
+    }
+
+    #[test]
+    fn grow_only_reports_shrinking() {
+        let p = Policy::new(vec![rule(&["/etc/**"], Some(vec![Attr::GrowOnly]), Severity::Alert)]).unwrap();
+        assert!(p.changes(&passwd(), &file("/etc/passwd", SHA_B, MTIME, 200, 0o644)).is_empty());
+        assert!(matches!(p.changes(&passwd(), &file("/etc/passwd", SHA_B, MTIME, 50, 0o644))[..],
+            [Event::ContentChanged { .. }]));
+    }
+
+    #[test]
+    fn link_count_changes_are_reported() {
+        let linked = stat(|p| {
+            std::fs::write(p, b"").unwrap();
+            std::fs::set_permissions(p, std::os::unix::fs::PermissionsExt::from_mode(0o644)).unwrap();
+            std::fs::hard_link(p, p.with_extension("2")).unwrap();
+        });
+        let new = ShaState::new(PathBuf::from("/etc/passwd"), SHA_A.parse().unwrap(), at(MTIME), 100).with_meta(&linked);
+        assert!(matches!(Policy::default().changes(&passwd(), &new)[..], [Event::LinksChanged { old_links: 1, new_links: 2, .. }]));
+    }
+
+    #[test]
+    fn type_and_target_changes_are_always_reported() {
+        let p = Policy::new(vec![rule(&["/etc/**"], Some(vec![Attr::Mtime]), Severity::Info)]).unwrap();
+        assert!(matches!(p.changes(&passwd(), &link("/tmp/passwd"))[..],
+            [Event::TypeChanged { old_kind: FileKind::File, new_kind: FileKind::Symlink, .. }]));
+        assert!(matches!(p.changes(&link("/tmp/passwd"), &link("/tmp/shadow"))[..], [Event::TargetChanged { .. }]));
+        assert!(p.changes(&link("/tmp/passwd"), &link("/tmp/passwd")).is_empty());
+    }
+
+    #[test]
+    fn directory_mtimes_are_not_changes() {
+        let dir = |secs| ShaState::special(PathBuf::from("/etc"), &stat(|p| {
+            std::fs::create_dir(p).unwrap();
+            std::fs::File::open(p).unwrap().set_modified(at(secs)).unwrap();
+        }), None);
+        assert_ne!(dir(MTIME).mtime(), dir(MTIME + 1).mtime());
+        assert!(Policy::default().changes(&dir(MTIME), &dir(MTIME + 1)).is_empty());
+    }
+
+    #[test]
+    fn mark_is_the_prior_size_of_append_only_files() {
+        let old = file("/var/log/audit.log", SHA_A, MTIME, 100, 0o600);
+        assert_eq!(append_only().mark(&old, HashAlgo::Sha1), Some(100));
+        // a prefix hash in another algorithm could never match the kept one
+        assert_eq!(append_only().mark(&old, HashAlgo::Sha256), None);
+        assert_eq!(Policy::default().mark(&old, HashAlgo::Sha1), None);
+    }
+
+    #[test]
+    fn append_only_tells_appends_from_rewrites() {
+        let p = append_only();
+        let log = |sha, size| file("/var/log/audit.log", sha, MTIME, size, 0o600);
+        let old = log(SHA_A, 100);
+
+        assert!(p.changes(&old, &log(SHA_A, 100)).is_empty());
+        let appended = p.changes(&old, &log(SHA_B, 150).with_prefix(Some(SHA_A.parse().unwrap())));
+        assert!(matches!(appended[..], [Event::Appended { old_size: 100, new_size: 150, .. }]));
+        let rewritten = p.changes(&old, &log(SHA_B, 150).with_prefix(Some(SHA_C.parse().unwrap())));
+        assert!(matches!(rewritten[..], [Event::Rewritten { .. }]));
+        assert!(matches!(p.changes(&old, &log(SHA_B, 50))[..], [Event::Truncated { old_size: 100, new_size: 50, .. }]));
+        // no prefix hash to go by, and the rule does not check content
+        assert!(p.changes(&old, &log(SHA_B, 150)).is_empty());
+
+        // appending is the expected change, rewriting history is what alerts
+        assert_eq!(p.severity_of(&appended[0]), Severity::Info);
+        assert_eq!(p.severity_of(&rewritten[0]), Severity::Alert);
+    }
+
+    #[test]
+    fn legacy_state_compares_only_content() {
+        let set = ShaSet::read(legacy_state().as_bytes(), &StateKeys::default(), Path::new("legacy.json")).unwrap();
+        let old = set.get(Path::new("/var/log/audit.log")).unwrap();
+        assert_eq!(old.size(), None);
+        assert_eq!(old.mode(), None);
+
+        let same = file("/var/log/audit.log", SHA_A, MTIME, 11, 0o600);
+        assert!(Policy::default().changes(old, &same).is_empty());
+        assert!(append_only().changes(old, &same).is_empty());
+        // no size to hash a prefix of, so an append only file is not read twice
+        assert_eq!(append_only().mark(old, HashAlgo::Sha1), None);
+
+        let edited = file("/var/log/audit.log", SHA_B, MTIME, 11, 0o600);
+        assert!(matches!(Policy::default().changes(old, &edited)[..], [Event::ContentChanged { mtime_changed: false, .. }]));
+    }
+}
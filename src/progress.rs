@@ -0,0 +1,179 @@
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use log::info;
+
+use crate::device_pool::DevicePools;
+use crate::scanner::{Record, Stats};
+use crate::sha_state::{FileKind, ShaSet};
+use crate::worker_queue::WorkerQueue;
+
+/// how progress is shown while a scan runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressMode {
+    /// a single updating line when stderr is a terminal, log lines otherwise
+    Auto,
+    Line,
+    Log,
+    Off,
+}
+
+impl FromStr for ProgressMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(ProgressMode::Auto),
+            "line" => Ok(ProgressMode::Line),
+            "log" => Ok(ProgressMode::Log),
+            "off" => Ok(ProgressMode::Off),
+            _ => Err(anyhow!("unknown progress mode \"{}\", expected auto, line, log or off", s)),
+        }
+    }
+}
+
+impl ProgressMode {
+    pub const VARIANTS: &'static [&'static str] = &["auto", "line", "log", "off"];
+}
+
+/// totals from the prior run used to guess how much is left
+#[derive(Debug, Clone, Copy)]
+pub struct PriorTotals {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+impl PriorTotals {
+    /// the files under `roots` the prior run hashed, the ones a scan counts as it goes
+    pub fn of(set: &ShaSet, roots: &[PathBuf]) -> Self {
+        let under = |p: &Path| roots.iter().any(|r| p.starts_with(r));
+        set.iter()
+            .filter(|e| e.kind() == FileKind::File && !e.is_unreadable() && under(e.path()))
+            .fold(PriorTotals { files: 0, bytes: 0 }, |t, e| {
+                PriorTotals { files: t.files + 1, bytes: t.bytes + e.size().unwrap_or(0) }
+            })
+    }
+}
+
+///
+/// Everything the progress thread looks at
+///
+/// Only clones and handles are held, so nothing here keeps the scan from finishing.
+pub struct Progress {
+    pub mode: ProgressMode,
+    pub log_every: Duration,
+    pub stats: Arc<Stats>,
+    pub dir_q: WorkerQueue<Option<PathBuf>>,
+    pub pools: DevicePools,
+    pub state_q: Sender<Option<Record>>,
+    pub prior: PriorTotals,
+}
+
+struct Snapshot {
+    at: Instant,
+    fc: usize,
+    bc: usize,
+}
+
+impl Progress {
+    ///
+    /// Report progress until `stopped` is dropped or sent to
+    ///
+    /// Line mode redraws every second, log mode writes one line every `log_every`.
+    pub fn run(self, stopped: Receiver<()>) {
+        let line = match self.mode {
+            ProgressMode::Off => {
+                let _ = stopped.recv();
+                return;
+            }
+            ProgressMode::Auto => std::io::stderr().is_terminal(),
+            ProgressMode::Line => true,
+            ProgressMode::Log => false,
+        };
+        let tick = if line { Duration::from_secs(1) } else { self.log_every };
+        let start = Snapshot { at: Instant::now(), fc: 0, bc: 0 };
+        let mut last = Snapshot { at: start.at, fc: 0, bc: 0 };
+        while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(tick) {
+            let now = Snapshot {
+                at: Instant::now(),
+                fc: self.stats.fc.load(Ordering::Relaxed),
+                bc: self.stats.bc.load(Ordering::Relaxed),
+            };
+            if line {
+                let mut err = std::io::stderr();
+                let _ = write!(err, "\r{}\x1b[K", self.render(&start, &last, &now));
+                let _ = err.flush();
+            } else {
+                info!("PROGRESS {}", self.render(&start, &last, &now));
+                for (dev, fc, bc) in self.pools.device_stats() {
+                    info!("PROGRESS dev={} files={} mb={:.1}", dev, fc, mb(bc as f64));
+                }
+            }
+            last = now;
+        }
+        if line {
+            eprintln!();
+        }
+    }
+
+    fn render(&self, start: &Snapshot, last: &Snapshot, now: &Snapshot) -> String {
+        let secs = now.at.duration_since(last.at).as_secs_f64().max(0.001);
+        let files_rate = (now.fc - last.fc) as f64 / secs;
+        let mb_rate = mb((now.bc - last.bc) as f64) / secs;
+        let dirs = self.dir_q.get_stats().curr_q_len;
+        format!("files={} mb={:.1} files/s={:.0} mb/s={:.1} dirs_pending={} sha_q={} state_q={} active={} eta={}",
+            now.fc, mb(now.bc as f64), files_rate, mb_rate, dirs,
+            self.pools.queue_depth(), self.state_q.len(), self.pools.busy(),
+            self.eta(start, now))
+    }
+
+    /// remaining time from the prior run's totals and the average rate so far
+    fn eta(&self, start: &Snapshot, now: &Snapshot) -> String {
+        let secs = now.at.duration_since(start.at).as_secs_f64();
+        let left = if self.prior.bytes > 0 && now.bc > 0 {
+            let left = self.prior.bytes.saturating_sub(now.bc as u64) as f64;
+            left / (now.bc as f64 / secs)
+        } else if self.prior.files > 0 && now.fc > 0 {
+            let left = self.prior.files.saturating_sub(now.fc) as f64;
+            left / (now.fc as f64 / secs)
+        } else {
+            return String::from("?");
+        };
+        let left = left as u64;
+        format!("{}:{:02}:{:02}", left / 3600, left / 60 % 60, left % 60)
+    }
+}
+
+fn mb(b: f64) -> f64 {
+    b / (1024.0 * 1024.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::ErrorKind;
+    use crate::sha_state::testing::{entry, set, stat, SHA_A, SHA_B};
+    use crate::sha_state::ShaState;
+
+    #[test]
+    fn prior_totals_count_readable_files_under_the_roots() {
+        let dir = ShaState::special(PathBuf::from("/etc/ssh"), &stat(|p| std::fs::create_dir(p).unwrap()), None);
+        let state = set([
+            entry("/etc/passwd", SHA_A, 100),
+            entry("/etc/group", SHA_B, 20),
+            entry("/var/log/syslog", SHA_A, 1000),
+            dir,
+            ShaState::unreadable(PathBuf::from("/etc/shadow"), ErrorKind::Open),
+        ]);
+        let t = PriorTotals::of(&state, &[PathBuf::from("/etc")]);
+        assert_eq!((t.files, t.bytes), (2, 120));
+        let t = PriorTotals::of(&state, &[PathBuf::from("/etc"), PathBuf::from("/var")]);
+        assert_eq!((t.files, t.bytes), (3, 1120));
+    }
+}
@@ -0,0 +1,134 @@
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use log::trace;
+
+///
+/// Order in which files found in one directory are handed to the sha threads
+///
+/// On spinning disks the order files are read in matters a lot more than the thread count.
+/// Sorting a directory's files by inode number or by where their first block lives on disk
+/// keeps the heads moving mostly forward instead of seeking all over the platter.
+///
+/// The order only holds with one sha thread per pool, with more they pull files off the queue
+/// side by side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadOrder {
+    /// order returned by read_dir - whatever the filesystem gives us
+    Walk,
+    /// sort by inode number - cheap since we already have the metadata
+    Inode,
+    /// sort by the physical offset of the first extent via the FIEMAP ioctl
+    Extent,
+}
+
+impl FromStr for ReadOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "walk" => Ok(ReadOrder::Walk),
+            "inode" => Ok(ReadOrder::Inode),
+            "extent" => Ok(ReadOrder::Extent),
+            _ => Err(anyhow!("unknown read order \"{}\", expected walk, inode or extent", s)),
+        }
+    }
+}
+
+impl ReadOrder {
+    pub const VARIANTS: &'static [&'static str] = &["walk", "inode", "extent"];
+
+    pub fn name(&self) -> &'static str {
+        ReadOrder::VARIANTS[*self as usize]
+    }
+
+    /// sort a batch of files from a single directory in place
+    pub fn sort_batch(&self, batch: &mut [(PathBuf, Metadata)]) {
+        match self {
+            ReadOrder::Walk => (),
+            ReadOrder::Inode => batch.sort_by_key(|(_, md)| inode_of(md)),
+            ReadOrder::Extent => {
+                // files without a mapping (empty, inline data, or no FIEMAP support) sort
+                // by inode after all the mapped ones
+                batch.sort_by_cached_key(|(path, md)| match first_physical_offset(path) {
+                    Some(off) => (0, off),
+                    None => (1, inode_of(md)),
+                });
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn inode_of(md: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    md.ino()
+}
+
+#[cfg(not(unix))]
+fn inode_of(_md: &Metadata) -> u64 {
+    0
+}
+
+#[cfg(target_os = "linux")]
+mod fiemap {
+    // layouts from linux/fiemap.h - only the first extent is asked for
+    #[repr(C)]
+    #[derive(Default)]
+    pub struct FiemapExtent {
+        pub fe_logical: u64,
+        pub fe_physical: u64,
+        pub fe_length: u64,
+        pub fe_reserved64: [u64; 2],
+        pub fe_flags: u32,
+        pub fe_reserved: [u32; 3],
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    pub struct Fiemap {
+        pub fm_start: u64,
+        pub fm_length: u64,
+        pub fm_flags: u32,
+        pub fm_mapped_extents: u32,
+        pub fm_extent_count: u32,
+        pub fm_reserved: u32,
+        pub fm_extents: [FiemapExtent; 1],
+    }
+
+    // _IOWR('f', 11, struct fiemap)
+    pub const FS_IOC_FIEMAP: libc::c_ulong = 0xC020_660B;
+    /// the extent has no place on disk yet, e.g. delayed allocation
+    pub const FIEMAP_EXTENT_UNKNOWN: u32 = 0x0000_0002;
+}
+
+/// physical byte offset of the first extent of a file, if the filesystem will tell us
+#[cfg(target_os = "linux")]
+pub fn first_physical_offset(path: &Path) -> Option<u64> {
+    use std::os::unix::io::AsRawFd;
+
+    let file = std::fs::File::open(path).ok()?;
+    let mut fm = fiemap::Fiemap {
+        fm_length: u64::MAX,
+        // no FIEMAP_FLAG_SYNC, flushing every file's dirty pages just to sort them costs more
+        // than it saves - files with delayed allocation sort by inode instead
+        fm_extent_count: 1,
+        ..Default::default()
+    };
+    let rc = unsafe { libc::ioctl(file.as_raw_fd(), fiemap::FS_IOC_FIEMAP as _, &mut fm as *mut fiemap::Fiemap) };
+    if rc != 0 {
+        trace!("FIEMAP failed on {}: {}", path.display(), std::io::Error::last_os_error());
+        return None;
+    }
+    if fm.fm_mapped_extents == 0 || fm.fm_extents[0].fe_flags & fiemap::FIEMAP_EXTENT_UNKNOWN != 0 {
+        return None;
+    }
+    Some(fm.fm_extents[0].fe_physical)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn first_physical_offset(_path: &Path) -> Option<u64> {
+    None
+}
@@ -0,0 +1,1086 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::{self, symlink_metadata, FileType};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::spawn;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{anyhow, Context, Result};
+use crossbeam_channel::{Receiver, Sender};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use log::{debug, error, info, trace, warn};
+use serde::Serialize;
+
+use crate::device_pool::{self, DevStats, DevicePools, DeviceThreads, FileQueue};
+use crate::hasher::{HashAlgo, HashValue};
+use crate::hooks::{run_on_complete, HookSink, Hooks};
+use crate::mem_budget::{self, MemPlan};
+use crate::policy::{Policy, Severity};
+use crate::progress::{PriorTotals, Progress, ProgressMode};
+use crate::read_order::ReadOrder;
+use crate::events::{Dispatcher, ErrorKind, Event, EventSink, FnSink};
+use crate::sha_state::{DiffResult, FileKind, ShaSet, ShaState};
+use crate::state_backend::StateBackend;
+use crate::tuning::{self, ThreadPlan};
+use crate::worker_queue::WorkerQueue;
+
+/// files and bytes hashed so far in a scan
+#[derive(Default)]
+pub struct Stats {
+    pub fc: AtomicUsize,
+    pub bc: AtomicUsize,
+}
+
+/// what the walking and sha threads hand to the state recording thread
+pub enum Record {
+    Hashed(ShaState),
+    Failed(PathBuf, Failure),
+    /// changing as it was read, the prior entry stands
+    Unstable(PathBuf, String),
+}
+
+/// why a path could not be scanned
+#[derive(Debug)]
+pub struct Failure {
+    pub kind: ErrorKind,
+    pub error: String,
+    /// how far into the file a failed read got
+    pub offset: Option<u64>,
+}
+
+impl Failure {
+    pub fn new(kind: ErrorKind, error: impl std::fmt::Display) -> Self {
+        Failure { kind, error: error.to_string(), offset: None }
+    }
+
+    pub fn event(self, path: PathBuf) -> Event {
+        Event::Error { path, kind: self.kind, error: self.error, offset: self.offset }
+    }
+}
+
+///
+/// How hard to try for a clean read of a file that is being written
+///
+/// The size, mtime and ctime are taken before and after each read, and a read they do not agree
+/// across is tried again after 100ms, doubling each time.  A file still changing after the
+/// last retry, or changed within `settle` of being opened, keeps its prior entry and is reported
+/// as unstable instead of as a torn hash.
+#[derive(Debug, Clone, Copy)]
+pub struct Stability {
+    pub retries: u32,
+    pub settle: Option<Duration>,
+}
+
+impl Default for Stability {
+    fn default() -> Self {
+        Stability { retries: 3, settle: None }
+    }
+}
+
+///
+/// What a scan did
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ScanSummary {
+    pub files: usize,
+    pub bytes: usize,
+    pub secs: f64,
+    pub added: usize,
+    pub sha_changed: usize,
+    pub time_changed: usize,
+    pub both_changed: usize,
+    /// only the mode or owner changed
+    pub perms_changed: usize,
+    pub unchanged: usize,
+    pub deleted: usize,
+    pub moved: usize,
+    pub errors: usize,
+    pub errors_by_kind: BTreeMap<ErrorKind, usize>,
+    /// every path that could not be scanned and what failed
+    pub failures: BTreeMap<PathBuf, ErrorKind>,
+    /// changes the policy rates as alerts
+    pub alerts: usize,
+    /// files left as they were in the state because they were changing as they were read
+    pub unstable: usize,
+    /// files whose content changed under the same mtime and size and read back the same again,
+    /// also counted as changed
+    pub corrupted: Vec<PathBuf>,
+    pub dir_threads: usize,
+    pub sha_threads: usize,
+    /// on change and on complete hooks that failed or timed out
+    pub hook_failures: Vec<String>,
+    /// the same counts for each root
+    pub roots: Vec<RootSummary>,
+}
+
+/// what a scan found under one of its roots
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RootSummary {
+    pub root: PathBuf,
+    pub files: usize,
+    pub bytes: u64,
+    pub added: usize,
+    pub changed: usize,
+    pub unchanged: usize,
+    pub deleted: usize,
+    pub moved: usize,
+    pub errors: usize,
+}
+
+impl RootSummary {
+    fn count(&mut self, diff: DiffResult) {
+        match diff {
+            DiffResult::Added => self.added += 1,
+            DiffResult::Same => self.unchanged += 1,
+            _ => self.changed += 1,
+        }
+    }
+}
+
+impl ScanSummary {
+    /// files whose content, time, mode or owner changed from the prior state
+    pub fn changed(&self) -> usize {
+        self.sha_changed + self.time_changed + self.both_changed + self.perms_changed
+    }
+
+    /// count a path that could not be scanned, and give a file that could not be read an entry
+    /// so it is not lost track of - true when one was added
+    pub fn record_error(&mut self, set: &mut ShaSet, path: &Path, kind: ErrorKind) -> bool {
+        self.count_error(path, kind);
+        matches!(kind, ErrorKind::Open | ErrorKind::Read) && set.get(path).is_none()
+            && set.add(ShaState::unreadable(path.to_path_buf(), kind)).is_ok()
+    }
+
+    /// count a path that could not be scanned
+    pub fn count_error(&mut self, path: &Path, kind: ErrorKind) {
+        self.errors += 1;
+        *self.errors_by_kind.entry(kind).or_default() += 1;
+        self.failures.insert(path.to_path_buf(), kind);
+    }
+
+    pub fn count(&mut self, diff: DiffResult) {
+        match diff {
+            DiffResult::Added => self.added += 1,
+            DiffResult::BothDiff => self.both_changed += 1,
+            DiffResult::ShaDiff => self.sha_changed += 1,
+            DiffResult::TimeDiff => self.time_changed += 1,
+            DiffResult::PermsDiff => self.perms_changed += 1,
+            DiffResult::Same => self.unchanged += 1,
+        }
+    }
+}
+
+/// how to count a file seen again, going by the events the policy made of its changes
+pub(crate) fn change_kind(evs: &[Event]) -> DiffResult {
+    match evs.first() {
+        None => DiffResult::Same,
+        Some(Event::ContentChanged { mtime_changed: true, .. }) => DiffResult::BothDiff,
+        Some(Event::ContentChanged { .. } | Event::Corruption { .. }) => DiffResult::ShaDiff,
+        Some(Event::MetadataChanged { .. }) => DiffResult::TimeDiff,
+        Some(Event::Appended { .. } | Event::Rewritten { .. } | Event::Truncated { .. }) => DiffResult::ShaDiff,
+        Some(Event::TypeChanged { .. } | Event::TargetChanged { .. }) => DiffResult::ShaDiff,
+        Some(_) => DiffResult::PermsDiff,
+    }
+}
+
+///
+/// Scan a tree, hash every file and compare against the prior state
+///
+/// Built up with the setters and then consumed by `run`:
+///
+/// ```no_run
+/// use shafiles::scanner::Scanner;
+/// use shafiles::state_backend::JsonFile;
+///
+/// let summary = Scanner::new("/etc", JsonFile::new("/var/lib/shafiles/etc.json"))
+///     .threads_sha(2)
+///     .exclude("**/*.swp")
+///     .on_change(|ev| println!("{} {}", ev.name(), ev.path().display()))
+///     .run()?;
+/// println!("{} files changed", summary.changed());
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub struct Scanner {
+    top_dirs: Vec<PathBuf>,
+    state: Box<dyn StateBackend>,
+    excludes: Vec<String>,
+    accept_corruption: Vec<String>,
+    threads_dir: Option<usize>,
+    threads_sha: Option<usize>,
+    per_device: bool,
+    device_threads: Vec<DeviceThreads>,
+    auto_tune: bool,
+    read_order: ReadOrder,
+    follow_symlinks: bool,
+    hash: HashAlgo,
+    buffer_size: usize,
+    file_queue: usize,
+    state_queue: usize,
+    max_memory: Option<usize>,
+    progress: ProgressMode,
+    progress_every: Duration,
+    sinks: Vec<Box<dyn EventSink>>,
+    policy: Policy,
+    stability: Stability,
+    hooks: Hooks,
+    stats: Arc<Stats>,
+}
+
+impl Scanner {
+    pub fn new(top_dir: impl Into<PathBuf>, state: impl StateBackend + 'static) -> Self {
+        Scanner {
+            top_dirs: vec![top_dir.into()],
+            state: Box::new(state),
+            excludes: vec![],
+            accept_corruption: vec![],
+            threads_dir: None,
+            threads_sha: None,
+            per_device: false,
+            device_threads: vec![],
+            auto_tune: false,
+            read_order: ReadOrder::Walk,
+            follow_symlinks: false,
+            hash: HashAlgo::Sha1,
+            buffer_size: 64 * 1024 * 1024,
+            file_queue: 10000,
+            state_queue: 10000,
+            max_memory: None,
+            progress: ProgressMode::Off,
+            progress_every: Duration::from_secs(10),
+            sinks: vec![],
+            policy: Policy::default(),
+            stability: Stability::default(),
+            hooks: Hooks::default(),
+            stats: Arc::new(Stats::default()),
+        }
+    }
+
+    /// another tree to walk in the same run and record in the same state
+    pub fn root(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.top_dirs.push(dir.into());
+        self
+    }
+
+    /// skip files and whole directories whose path matches this glob, e.g. "**/.git"
+    pub fn exclude(mut self, glob: impl Into<String>) -> Self {
+        self.excludes.push(glob.into());
+        self
+    }
+
+    /// take the new hash of corrupt files matching this glob, so they are reported once and not
+    /// on every scan until restored
+    pub fn accept_corruption(mut self, glob: impl Into<String>) -> Self {
+        self.accept_corruption.push(glob.into());
+        self
+    }
+
+    /// directory scanning threads, None to pick from the cpu count and disk type
+    pub fn threads_dir(mut self, n: impl Into<Option<usize>>) -> Self {
+        self.threads_dir = n.into();
+        self
+    }
+
+    /// sha threads, None to pick from the cpu count and disk type
+    pub fn threads_sha(mut self, n: impl Into<Option<usize>>) -> Self {
+        self.threads_sha = n.into();
+        self
+    }
+
+    /// a sha thread pool per device, with optional counts for some devices
+    pub fn per_device(mut self, on: bool, device_threads: Vec<DeviceThreads>) -> Self {
+        self.per_device = on;
+        self.device_threads = device_threads;
+        self
+    }
+
+    pub fn auto_tune(mut self, on: bool) -> Self {
+        self.auto_tune = on;
+        self
+    }
+
+    pub fn read_order(mut self, order: ReadOrder) -> Self {
+        self.read_order = order;
+        self
+    }
+
+    /// hash what links to files point to and walk links to directories, instead of recording the links
+    pub fn follow_symlinks(mut self, on: bool) -> Self {
+        self.follow_symlinks = on;
+        self
+    }
+
+    pub fn hash(mut self, algo: HashAlgo) -> Self {
+        self.hash = algo;
+        self
+    }
+
+    pub fn buffer_size(mut self, bytes: usize) -> Self {
+        self.buffer_size = bytes;
+        self
+    }
+
+    /// capacity of the sha and state queues, 0 for unbounded
+    pub fn queues(mut self, file_queue: usize, state_queue: usize) -> Self {
+        self.file_queue = file_queue;
+        self.state_queue = state_queue;
+        self
+    }
+
+    pub fn max_memory(mut self, bytes: impl Into<Option<usize>>) -> Self {
+        self.max_memory = bytes.into();
+        self
+    }
+
+    /// progress display, off by default for embedded use
+    pub fn progress(mut self, mode: ProgressMode, log_every: Duration) -> Self {
+        self.progress = mode;
+        self.progress_every = log_every;
+        self
+    }
+
+    /// add somewhere for change events to go, any number may be added
+    pub fn sink(mut self, sink: Box<dyn EventSink>) -> Self {
+        self.sinks.push(sink);
+        self
+    }
+
+    /// called for every change event - send to a channel from here to stream them
+    pub fn on_change(self, f: impl FnMut(&Event) + Send + 'static) -> Self {
+        self.sink(Box::new(FnSink(f)))
+    }
+
+    /// which changes matter where and how much, everything at info without one
+    pub fn policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// retries for files changing as they are read and how long a file must be left alone first
+    pub fn stability(mut self, stability: Stability) -> Self {
+        self.stability = stability;
+        self
+    }
+
+    /// commands to run per change and at the end of the run
+    pub fn hooks(mut self, hooks: Hooks) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    /// files and bytes hashed so far, to watch a run from another thread
+    pub fn stats(&self) -> Arc<Stats> {
+        self.stats.clone()
+    }
+
+    /// run the scan, save the new state and return what happened
+    pub fn run(mut self) -> Result<ScanSummary> {
+        let excludes = build_globs(&self.excludes)?;
+        let roots = distinct_roots(&self.top_dirs);
+        let mut threads = ThreadPlan::new(&roots, self.threads_dir, self.threads_sha)?;
+        // a sorted order only holds when each pool reads one file at a time
+        let sorted = self.read_order != ReadOrder::Walk;
+        if sorted && threads.auto_sha && threads.sha_threads > 1 {
+            info!("{} read order: 1 sha thread per pool", self.read_order.name());
+            threads.sha_threads = 1;
+        } else if sorted && threads.sha_threads > 1 {
+            warn!("with {} sha threads files are read only roughly in {} order", threads.sha_threads, self.read_order.name());
+        }
+        let auto_pools = threads.auto_sha && !sorted;
+        // auto tuning may make up to twice the threads active, and each active one holds a buffer
+        let tune = if self.auto_tune { 2 } else { 1 };
+        let devices = if self.per_device { device_pool::devices_under(&roots) } else { BTreeSet::new() };
+        // pools picking their own thread count are fitted by the largest of them
+        let pool_threads = match auto_pools && self.per_device {
+            true => devices.iter().map(|&d| tuning::default_sha_threads(tuning::is_rotational(d))).max().unwrap_or(threads.sha_threads),
+            false => threads.sha_threads,
+        }.max(self.device_threads.iter().map(|d| d.threads).max().unwrap_or(0));
+        let mut plan = MemPlan {
+            sha_threads: pool_threads * tune,
+            buffer_size: self.buffer_size,
+            file_queue: self.file_queue,
+            state_queue: self.state_queue,
+        };
+        let mut max_sha_threads = usize::MAX;
+        if let Some(budget) = self.max_memory {
+            plan.fit(budget, devices.len().max(1))?;
+            max_sha_threads = plan.sha_threads;
+        }
+        let sha_threads = threads.sha_threads.min(max_sha_threads);
+
+        let stats = self.stats.clone();
+        let mut dir_q: WorkerQueue<Option<PathBuf>> = WorkerQueue::new(threads.dir_threads, 0);
+        let (send_state, recv_state) = mem_budget::channel(plan.state_queue);
+
+        self.state.lock()?;
+        let set = self.state.load()?;
+        if let Some(e) = set.iter().find(|e| e.kind() == FileKind::File && !e.is_unreadable()) {
+            if e.sha().algo() != self.hash {
+                warn!("state {} holds {} hashes but this run uses {} - every file will show as changed",
+                    self.state.describe(), e.sha().algo(), self.hash);
+            }
+        }
+        let prior = PriorTotals::of(&set, &roots);
+        // how much of each append only file the prior run saw, to hash that part on the way past
+        let marks: HashMap<PathBuf, u64> = set.iter()
+            .filter_map(|e| self.policy.mark(e, self.hash).map(|m| (e.path().to_path_buf(), m)))
+            .collect();
+        let started = SystemTime::now();
+        let state = Arc::new(Mutex::new(set));
+
+        let start = Instant::now();
+
+        if let Some(cmd) = &self.hooks.on_change {
+            self.sinks.push(Box::new(HookSink::new(cmd, &self.hooks)));
+        }
+        // the on change hook's and any exec sinks'
+        let hook_failures: Vec<_> = self.sinks.iter().filter_map(|s| s.failures()).collect();
+
+        let h_state_write = {
+            let state_c = state.clone();
+            let events = Dispatcher::new(std::mem::take(&mut self.sinks), std::mem::take(&mut self.policy));
+            let roots = roots.clone();
+            let accepted = build_globs(&self.accept_corruption)?;
+            spawn(move || record_state(recv_state, &state_c, events, roots, accepted))
+        };
+
+        let pools = {
+            let send_state = send_state.clone();
+            let stats = stats.clone();
+            let opts = ShaOpts { buf_size: plan.buffer_size, hash: self.hash, marks: Arc::new(marks), stability: self.stability,
+                links: Arc::new(Links::default()) };
+            DevicePools::new(self.per_device, sha_threads, auto_pools, self.auto_tune, plan.file_queue, &self.device_threads,
+                             Box::new(move |recv, dev_stats, idx| {
+                let send_state = send_state.clone();
+                let stats = stats.clone();
+                let opts = opts.clone();
+                spawn(move || sha_files(&recv, &send_state, &stats, &dev_stats, idx, &opts))
+            }))?
+            .max_threads(max_sha_threads)
+        };
+
+        let (stop_progress, h_progress) = {
+            let progress = Progress {
+                mode: self.progress,
+                log_every: self.progress_every,
+                stats: stats.clone(),
+                dir_q: dir_q.clone(),
+                pools: pools.clone(),
+                state_q: send_state.clone(),
+                prior,
+            };
+            let (stop, stopped) = crossbeam_channel::bounded::<()>(0);
+            (stop, spawn(move || progress.run(stopped)))
+        };
+
+        let (stop_tune, h_tuner) = if self.auto_tune {
+            let pools = pools.clone();
+            let (stop, stopped) = crossbeam_channel::bounded::<()>(0);
+            (Some(stop), Some(spawn(move || auto_tune(pools, stopped))))
+        } else {
+            (None, None)
+        };
+
+        let excludes = Arc::new(excludes);
+        let visited = match self.follow_symlinks {
+            false => None,
+            true => Some(Arc::new(Mutex::new(roots.iter().filter_map(|r| fs::metadata(r).ok())
+                .map(|md| (md.dev(), md.ino())).collect::<HashSet<_>>()))),
+        };
+        let mut h_dir_threads = vec![];
+        for _i in 0..threads.dir_threads {
+            let mut dir_q = dir_q.clone();
+            let pools = pools.clone();
+            let order = self.read_order;
+            let excludes = excludes.clone();
+            let send_state = send_state.clone();
+            let visited = visited.clone();
+            let h = spawn(move || read_dir_thread(&mut dir_q, &pools, &send_state, order, &excludes, visited.as_deref()));
+            h_dir_threads.push(h);
+        }
+
+        // prime the read dir pump
+        for r in &roots {
+            dir_q.push(Some(r.clone()))?;
+        }
+
+
+        // wait on work as boss queue - then stop them
+        loop {
+            let x = dir_q.wait_for_finish_timeout(Duration::from_millis(250))?;
+            if x != -1 { break; }
+        }
+        for _ in 0..threads.dir_threads { dir_q.push(None)?; }
+        for h in h_dir_threads {
+            h.join().unwrap();
+        }
+        info!("directory scanning is done");
+
+        // wait on sha threads
+        drop(stop_tune);
+        if let Some(h) = h_tuner { h.join().unwrap(); }
+        let tot_bytes = pools.finish()?;
+        drop(stop_progress);
+        h_progress.join().unwrap();
+        let secs = start.elapsed().as_secs_f64();
+        let rate = (tot_bytes as f64 / secs)/(1024.0*1024.0);
+        info!("sha of files is done in {:.3} secs {} total  {:.2}MB/ sec", secs, tot_bytes, rate);
+        info!("{}", threads);
+
+        send_state.send(None)?;
+        let mut rec = h_state_write.join().map_err(|_| anyhow!("state recording thread panicked"))?;
+        {
+            let mut set = state.lock().unwrap();
+            finish_changes(&mut set, &mut rec, &excludes);
+            for r in &roots {
+                set.scanned(r, started);
+            }
+        }
+        rec.events.flush();
+        let mut summary = rec.summary;
+        summary.roots = rec.roots;
+        summary.alerts = rec.events.alerts;
+        for failures in hook_failures {
+            summary.hook_failures.append(&mut failures.lock().unwrap());
+        }
+        summary.files = stats.fc.load(Ordering::Relaxed);
+        summary.bytes = tot_bytes;
+        summary.secs = secs;
+        summary.dir_threads = threads.dir_threads;
+        summary.sha_threads = sha_threads;
+
+        match state.lock() { // this match is needed I think because LockGuard points to special version of Result
+            Err(_) => panic!("cannot lock state at the to write the current entries"),
+            Ok(s) => self.state.save(&s)?,
+        }
+
+        if let Err(e) = run_on_complete(&self.hooks, &serde_json::to_string(&summary)?) {
+            error!("{:#}", e);
+            summary.hook_failures.push(format!("{:#}", e));
+        }
+
+        Ok(summary)
+    }
+}
+
+/// the roots without repeats or roots inside other roots, which would be walked twice
+fn distinct_roots(dirs: &[PathBuf]) -> Vec<PathBuf> {
+    let mut roots: Vec<PathBuf> = vec![];
+    for d in dirs {
+        if let Some(r) = roots.iter().find(|r| d.starts_with(r)) {
+            warn!("{} is under root {} and will not be walked twice", d.display(), r.display());
+            continue;
+        }
+        roots.retain(|r| {
+            let inside = r.starts_with(d);
+            if inside {
+                warn!("{} is under root {} and will not be walked twice", r.display(), d.display());
+            }
+            !inside
+        });
+        roots.push(d.clone());
+    }
+    roots
+}
+
+pub(crate) fn build_globs(globs: &[String]) -> Result<GlobSet> {
+    let mut b = GlobSetBuilder::new();
+    for g in globs {
+        b.add(Glob::new(g).with_context(|| format!("bad exclude glob \"{}\"", g))?);
+    }
+    Ok(b.build()?)
+}
+
+/// directories walked so far by (dev, ino), only kept when following symlinks
+type Visited = Mutex<HashSet<(u64, u64)>>;
+
+/// whether `md` is a directory not walked yet, always when not following symlinks
+fn first_visit(follow: Option<&Visited>, md: &fs::Metadata) -> bool {
+    follow.is_none_or(|v| v.lock().unwrap().insert((md.dev(), md.ino())))
+}
+
+fn read_dir_thread(queue: &mut WorkerQueue<Option<PathBuf>>, pools: &DevicePools, send: &Sender<Option<Record>>,
+                   order: ReadOrder, excludes: &GlobSet, follow: Option<&Visited>) {
+    while let Err(e) = _read_dir_thread(queue, pools, send, order, excludes, follow) {
+        error!("read_dir thread top: {}", e);
+    }
+}
+
+///
+/// Walk directories off the queue, sending files to be hashed and recording everything else
+///
+/// Directories, symlinks, fifos, sockets and device nodes are recorded as they are found,
+/// from their own metadata.  When following symlinks a link to a file is hashed as that file
+/// and a link to a directory is walked, but only the first time a directory is reached, which
+/// also breaks loops.  Dangling links and links back into walked directories stay symlinks.
+fn _read_dir_thread(queue: &mut WorkerQueue<Option<PathBuf>>, pools: &DevicePools, send: &Sender<Option<Record>>,
+                    order: ReadOrder, excludes: &GlobSet, follow: Option<&Visited>) -> Result<()> {
+    let mut batch = vec![];
+    loop {
+        match queue.pop() {
+            None => return Ok(()),
+            Some(path) => {
+                trace!("scanning dir {}", path.display());
+                let dir_itr = match std::fs::read_dir(&path) {
+                    Err(e) => {
+                        error!("stat of dir: '{}', error: {}", path.display(), e);
+                        send.send(Some(Record::Failed(path, Failure::new(ErrorKind::ReadDir, e))))?;
+                        continue;
+                    }
+                    Ok(rd) => rd,
+                };
+                let dir = path;
+                for entry in dir_itr {
+                    // what was listed before the failure is still scanned, the rest keeps its prior entries
+                    let entry = match entry {
+                        Err(e) => {
+                            error!("listing dir: '{}', error: {}", dir.display(), e);
+                            send.send(Some(Record::Failed(dir.clone(), Failure::new(ErrorKind::ReadDir, e))))?;
+                            break;
+                        }
+                        Ok(entry) => entry,
+                    };
+                    let path = entry.path();
+                    if excludes.is_match(&path) {
+                        trace!("excluding {}", path.display());
+                        continue;
+                    }
+                    let md = match symlink_metadata(entry.path()) {
+                        Err(e) => {
+                            error!("stat of file for symlink: '{}', error: {}", path.display(), e);
+                            send.send(Some(Record::Failed(path, Failure::new(ErrorKind::Stat, e))))?;
+                            continue;
+                        }
+                        Ok(md) => md,
+                    };
+
+                    // followed, a link is taken as what it points to when that is there
+                    let link = md.clone();
+                    let md = match follow {
+                        Some(_) if link.file_type().is_symlink() => fs::metadata(&path).unwrap_or(md),
+                        _ => md,
+                    };
+                    let file_type: FileType = md.file_type();
+                    if file_type.is_file() {
+                        batch.push((path, md));
+                        continue;
+                    }
+                    let md = if file_type.is_dir() && !first_visit(follow, &md) {
+                        warn!("\"{}\" leads to a directory already walked, a symlink loop or a second link to it", path.display());
+                        link
+                    } else {
+                        if file_type.is_dir() {
+                            queue.push(Some(path.clone()))?;
+                        }
+                        md
+                    };
+                    let target = match md.file_type().is_symlink() {
+                        false => None,
+                        true => match fs::read_link(&path) {
+                            Ok(t) => Some(t),
+                            Err(e) => {
+                                send.send(Some(Record::Failed(path, Failure::new(ErrorKind::Stat, format!("read link: {}", e)))))?;
+                                continue;
+                            }
+                        },
+                    };
+                    send.send(Some(Record::Hashed(ShaState::special(path, &md, target))))?;
+                }
+                // files in a directory are sent as one sorted batch so that readers
+                // walk the disk in order rather than in directory listing order
+                order.sort_batch(&mut batch);
+                for (path, md) in batch.drain(..) {
+                    trace!("sending file {}", path.display());
+                    pools.send(md.dev(), path)?;
+                }
+            }
+        }
+    }
+}
+
+/// how the sha threads hash
+#[derive(Clone)]
+struct ShaOpts {
+    buf_size: usize,
+    hash: HashAlgo,
+    /// prior sizes of append only files
+    marks: Arc<HashMap<PathBuf, u64>>,
+    stability: Stability,
+    links: Arc<Links>,
+}
+
+fn sha_files(recv: &FileQueue, send: &Sender<Option<Record>>, stats: &Stats, dev_stats: &DevStats,
+             idx: usize, opts: &ShaOpts) -> usize {
+    let mut size = 0;
+    loop {
+        match _sha_files(recv, send, stats, dev_stats, idx, opts) {
+            Err(e) => {
+                error!("sha_file thread top: {}", e);
+            }
+            Ok(s) => {
+                size += s;
+                break
+            },
+        }
+    }
+    size
+}
+
+fn _sha_files(recv: &FileQueue, send: &Sender<Option<Record>>, stats: &Stats, dev_stats: &DevStats,
+              idx: usize, opts: &ShaOpts) -> Result<usize> {
+    // only threads auto tuning has made active hold a buffer
+    let mut buf = vec![];
+    let mut size = 0;
+    loop {
+        // parked by auto tuning
+        if idx >= dev_stats.active.load(Ordering::Relaxed) {
+            buf = vec![];
+            while idx >= dev_stats.active.load(Ordering::Relaxed) {
+                std::thread::sleep(Duration::from_millis(100));
+            }
+        }
+        trace!("waiting...");
+        match recv.recv()? {
+            None => return Ok(size), // this is the end my friend
+            Some(path) => {
+                if buf.is_empty() {
+                    buf = vec![0u8; opts.buf_size];
+                }
+                dev_stats.busy.fetch_add(1, Ordering::Relaxed);
+                let res = sha_a_file(&path, &mut buf, opts.hash, opts.marks.get(&path).copied(), opts.stability, Some(&opts.links));
+                dev_stats.busy.fetch_sub(1, Ordering::Relaxed);
+                match res {
+                    Err(e) => {
+                        error!("{} on file {} failed, {}", opts.hash, &path.display(), e.error);
+                        send.send(Some(Record::Failed(path, e)))?;
+                    }
+                    Ok(Hashed::Unstable(why)) => {
+                        debug!("\"{}\" {}, keeping its prior entry", path.display(), why);
+                        send.send(Some(Record::Unstable(path, why)))?;
+                    }
+                    Ok(Hashed::Done(state, sz)) => {
+                        size += sz;
+                        stats.bc.fetch_add(sz, Ordering::Relaxed);
+                        stats.fc.fetch_add(1, Ordering::Relaxed);
+                        dev_stats.bc.fetch_add(sz, Ordering::Relaxed);
+                        dev_stats.fc.fetch_add(1, Ordering::Relaxed);
+                        send.send(Some(Record::Hashed(*state)))?;
+                    }
+                }
+            }
+        };
+    }
+}
+
+/// a file hashed, or why it could not be read cleanly
+pub(crate) enum Hashed {
+    Done(Box<ShaState>, usize),
+    Unstable(String),
+}
+
+/// the hash of an inode with several links and the state of the inode it was taken at
+struct LinkHash {
+    digest: HashValue,
+    snapshot: (u64, i64, i64, i64, i64),
+}
+
+/// one slot per inode, locked while the first of its links is being hashed
+type LinkSlot = Arc<Mutex<Option<LinkHash>>>;
+
+///
+/// Hashes of files with more than one link, so each inode is only read once in a run
+///
+/// The first link to reach a sha thread holds its inode's slot while hashing, and any other
+/// link of it waits and then takes the hash, as long as the size, mtime and ctime still match.
+#[derive(Default)]
+pub(crate) struct Links(Mutex<HashMap<(u64, u64), LinkSlot>>);
+
+impl Links {
+    fn slot(&self, md: &fs::Metadata) -> LinkSlot {
+        self.0.lock().unwrap().entry((md.dev(), md.ino())).or_default().clone()
+    }
+}
+
+/// hash a file, and its first `mark` bytes too when given, retrying reads it changes under
+///
+/// With `links`, a file with several links takes the hash already made for another of them.
+/// Such a file is read again when it needs a hash of its first `mark` bytes.
+pub(crate) fn sha_a_file(path: &Path, buf: &mut [u8], hash: HashAlgo, mark: Option<u64>, stability: Stability,
+                         links: Option<&Links>) -> std::result::Result<Hashed, Failure> {
+    let stat_failed = |e| Failure::new(ErrorKind::Stat, e);
+    let mut wait = Duration::from_millis(100);
+    for tries in 0..=stability.retries {
+        let mut file = fs::File::open(path).map_err(|e| Failure::new(ErrorKind::Open, e))?;
+        let before = file.metadata().map_err(stat_failed)?;
+        if let Some(settle) = stability.settle {
+            let age = SystemTime::now().duration_since(changed_at(&before)).unwrap_or_default();
+            if age < settle {
+                return Ok(Hashed::Unstable(format!("changed {:.1} secs ago, within the settle time", age.as_secs_f64())));
+            }
+        }
+        let slot = match links {
+            Some(l) if mark.is_none() && before.nlink() > 1 => Some(l.slot(&before)),
+            _ => None,
+        };
+        let mut known = slot.as_ref().map(|s| s.lock().unwrap());
+        if let Some(Some(h)) = known.as_deref() {
+            if h.snapshot == snapshot(&before) {
+                trace!("path: \"{}\" {}: {} from another link", path.display(), hash, &h.digest);
+                let entry = ShaState::new(path.to_path_buf(), h.digest.clone(), before.modified().map_err(stat_failed)?, before.len());
+                return Ok(Hashed::Done(Box::new(entry.with_meta(&before)), 0));
+            }
+        }
+        let (digest, size, prefix) = hash.digest_reader_marked(&mut file, buf, mark)
+            .map_err(|e| Failure { offset: Some(e.offset), ..Failure::new(ErrorKind::Read, &e) })?;
+        let after = file.metadata().map_err(stat_failed)?;
+        if size as u64 == after.len() && snapshot(&before) == snapshot(&after) {
+            trace!("path: \"{}\" {}: {}", path.display(), hash, &digest);
+            if let Some(k) = known.as_deref_mut() {
+                *k = Some(LinkHash { digest: digest.clone(), snapshot: snapshot(&after) });
+            }
+            let entry = ShaState::new(path.to_path_buf(), digest, after.modified().map_err(stat_failed)?, size as u64).with_meta(&after);
+            return Ok(Hashed::Done(Box::new(entry.with_prefix(prefix)), size));
+        }
+        drop(known);
+        if tries < stability.retries {
+            debug!("\"{}\" changed while being read, trying again in {} ms", path.display(), wait.as_millis());
+            std::thread::sleep(wait);
+            wait *= 2;
+        }
+    }
+    Ok(Hashed::Unstable(format!("changed while being read {} times", stability.retries + 1)))
+}
+
+/// what a write to the file moves
+fn snapshot(md: &fs::Metadata) -> (u64, i64, i64, i64, i64) {
+    (md.len(), md.mtime(), md.mtime_nsec(), md.ctime(), md.ctime_nsec())
+}
+
+/// when the file or its inode last changed, the ctime, which unlike the mtime cannot be set back
+fn changed_at(md: &fs::Metadata) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::new(md.ctime().max(0) as u64, md.ctime_nsec() as u32)
+}
+
+///
+/// Re-read a file whose content changed while its mtime and size did not, which edits do not do
+///
+/// The file's page cache is dropped first so the re-read comes from the disk.  When it agrees
+/// with the first read the change becomes `Corruption`, when it gives the prior hash the first
+/// read was a glitch and there is no change.  Also gives whether the prior entry is to be kept:
+/// a corrupt file keeps its known good hash so it goes on being reported until it is restored,
+/// unless its path matches `accepted`, when the new hash is taken and it is reported this once.
+pub(crate) fn confirm_rot(mut evs: Vec<Event>, old: &ShaState, new: &ShaState, accepted: &GlobSet) -> (Vec<Event>, bool) {
+    let suspect = matches!(evs.first(), Some(Event::ContentChanged { mtime_changed: false, .. })) && old.size() == new.size();
+    if !suspect {
+        return (evs, false);
+    }
+    match reread(new.path(), new.sha().algo()) {
+        Ok(sha) if &sha == new.sha() => {
+            evs[0] = Event::Corruption { path: new.path().to_path_buf(), old_sha: old.sha().clone(), new_sha: sha, size: new.size().unwrap_or(0) };
+            if accepted.is_match(new.path()) {
+                info!("corruption of \"{}\" accepted, {} is kept from now on", new.path().display(), new.sha());
+                return (evs, false);
+            }
+            (evs, true)
+        }
+        Ok(sha) if &sha == old.sha() => {
+            warn!("\"{}\" read back as {} once but a re-read matches the state", new.path().display(), new.sha());
+            evs.remove(0);
+            (evs, true)
+        }
+        // changing as we read it
+        Ok(_) => (evs, false),
+        Err(e) => {
+            warn!("cannot re-read \"{}\" to confirm its change: {:#}", new.path().display(), e);
+            (evs, false)
+        }
+    }
+}
+
+/// hash a file again from the disk rather than the page cache
+fn reread(path: &Path, hash: HashAlgo) -> Result<HashValue> {
+    let file = fs::File::open(path).context("open failed")?;
+    // only clean pages are dropped, which is all an unchanged file has
+    unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+    let mut buf = vec![0u8; 1024 * 1024];
+    Ok(hash.digest_reader(&file, &mut buf)?.0)
+}
+
+/// what the state recording thread found, handed back when it is done
+struct Recorded {
+    summary: ScanSummary,
+    events: Dispatcher,
+    /// added entries are held back until the end so moves can be told apart from them
+    added: Vec<ShaState>,
+    seen: HashSet<PathBuf>,
+    /// paths that could not be listed or statted, whatever was under them keeps its prior entries
+    kept: Vec<PathBuf>,
+    roots: Vec<RootSummary>,
+}
+
+impl Recorded {
+    fn root(&mut self, p: &Path) -> Option<&mut RootSummary> {
+        self.roots.iter_mut().find(|r| p.starts_with(&r.root))
+    }
+}
+
+fn record_state(recv: Receiver<Option<Record>>, state: &Arc<Mutex<ShaSet>>, events: Dispatcher, roots: Vec<PathBuf>,
+                accepted: GlobSet) -> Recorded {
+    let roots = roots.into_iter().map(|root| RootSummary { root, ..Default::default() }).collect();
+    let mut rec = Recorded { summary: ScanSummary::default(), events, added: vec![], seen: HashSet::new(), kept: vec![], roots };
+    loop {
+        match recv.recv() {
+            Err(e) => panic!("write thread errored during receive: {}", e),
+            Ok(None) => return rec,
+            Ok(Some(Record::Failed(path, failure))) => {
+                rec.summary.record_error(&mut state.lock().unwrap(), &path, failure.kind);
+                if let Some(r) = rec.root(&path) {
+                    r.errors += 1;
+                }
+                rec.seen.insert(path.clone());
+                if matches!(failure.kind, ErrorKind::ReadDir | ErrorKind::Stat) {
+                    rec.kept.push(path.clone());
+                }
+                rec.events.emit(&failure.event(path));
+            }
+            Ok(Some(Record::Unstable(path, why))) => {
+                rec.summary.unstable += 1;
+                rec.seen.insert(path.clone());
+                rec.events.emit(&Event::Unstable { path, reason: why });
+            }
+            Ok(Some(Record::Hashed(state_entry))) => {
+                match state.lock() {
+                    Err(e) => panic!("write thread error locking state {}", e),
+                    Ok(mut state) => {
+                        let info = state_entry.to_string();
+                        let new = state_entry.clone();
+                        rec.seen.insert(new.path().to_path_buf());
+                        match state.add_with_prior(state_entry) {
+                            Err(e) => error!("Cannot add entry for {} due to {}", info, e),
+                            Ok((diff, old)) => {
+                                // read for the first time after being unreadable
+                                let (diff, old) = match old {
+                                    Some(o) if o.is_unreadable() => (DiffResult::Added, None),
+                                    o => (diff, o),
+                                };
+                                let evs = match &old {
+                                    Some(old) => {
+                                        let (evs, keep_old) = confirm_rot(rec.events.policy().changes(old, &new), old, &new, &accepted);
+                                        if keep_old {
+                                            let _ = state.add_with_prior(old.clone());
+                                        }
+                                        evs
+                                    }
+                                    None => vec![],
+                                };
+                                if let Some(Event::Corruption { path, .. }) = evs.first() {
+                                    rec.summary.corrupted.push(path.clone());
+                                }
+                                let diff = if diff == DiffResult::Added { diff } else { change_kind(&evs) };
+                                rec.summary.count(diff);
+                                if let Some(r) = rec.root(new.path()) {
+                                    r.count(diff);
+                                    if new.kind() == FileKind::File {
+                                        r.files += 1;
+                                        r.bytes += new.size().unwrap_or(0);
+                                    }
+                                }
+                                // added ones are held back to tell moves from additions
+                                if diff == DiffResult::Added {
+                                    rec.added.push(new);
+                                }
+                                for ev in evs {
+                                    rec.events.emit(&ev);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+///
+/// Work out deletions and moves once the walk is done and send the held back events
+///
+/// Only prior entries under the scanned roots that are not excluded can be deleted, so
+/// narrowing a scan does not drop the rest of the state, and nothing under a directory that
+/// could not be listed is taken as deleted.  A deleted entry whose content shows
+/// up as an added one is reported as a move instead, possibly across roots.  Empty files are
+/// never matched up as moves.
+fn finish_changes(set: &mut ShaSet, rec: &mut Recorded, excludes: &GlobSet) {
+    let gone: Vec<PathBuf> = set.iter()
+        .map(|e| e.path())
+        .filter(|p| rec.roots.iter().any(|r| p.starts_with(&r.root)) && !rec.seen.contains(*p) && !excludes.is_match(p))
+        .filter(|p| !rec.kept.iter().any(|k| p.starts_with(k)))
+        .map(|p| p.to_path_buf())
+        .collect();
+    let mut deleted: HashMap<_, Vec<ShaState>> = HashMap::new();
+    for p in gone {
+        if let Some(e) = set.remove(&p) {
+            deleted.entry(e.sha().clone()).or_default().push(e);
+        }
+    }
+    for new in std::mem::take(&mut rec.added) {
+        let from = match new.size() {
+            Some(0) => None,
+            _ => deleted.get_mut(new.sha()).and_then(|v| v.pop()),
+        };
+        match from {
+            Some(old) => {
+                rec.summary.added -= 1;
+                rec.summary.moved += 1;
+                if let Some(r) = rec.root(new.path()) {
+                    r.added -= 1;
+                    r.moved += 1;
+                }
+                rec.events.emit(&Event::Moved { from: old.path().to_path_buf(), to: new.path().to_path_buf(), sha: new.sha().clone() });
+            }
+            None => {
+                // the first scan of a root only takes stock, there is nothing to alert on yet
+                let cap = if set.root_of(new.path()).is_some() { Severity::Alert } else { Severity::Info };
+                rec.events.emit_at_most(&Event::Added { path: new.path().to_path_buf(), sha: new.sha().clone(), size: new.size().unwrap_or(0) }, cap);
+            }
+        }
+    }
+    let mut left: Vec<ShaState> = deleted.into_values().flatten().collect();
+    left.sort();
+    for old in left {
+        rec.summary.deleted += 1;
+        if let Some(r) = rec.root(old.path()) {
+            r.deleted += 1;
+        }
+        rec.events.emit(&Event::Deleted { path: old.path().to_path_buf(), sha: old.sha().clone() });
+    }
+}
+
+fn auto_tune(pools: DevicePools, stopped: Receiver<()>) {
+    let interval = Duration::from_secs(3);
+    let mut last = Instant::now();
+    while let Err(crossbeam_channel::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+        pools.tune(last.elapsed().as_secs_f64());
+        last = Instant::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sha_state::testing::{entry, set, SHA_A};
+
+    #[test]
+    fn unread_files_keep_an_entry() {
+        let mut state = set([entry("/etc/passwd", SHA_A, 100)]);
+        let mut summary = ScanSummary::default();
+        assert!(summary.record_error(&mut state, Path::new("/etc/shadow"), ErrorKind::Open));
+        assert!(state.get(Path::new("/etc/shadow")).unwrap().is_unreadable());
+        // a prior entry stands and a directory that cannot be listed keeps what was under it
+        assert!(!summary.record_error(&mut state, Path::new("/etc/passwd"), ErrorKind::Read));
+        assert!(!state.get(Path::new("/etc/passwd")).unwrap().is_unreadable());
+        assert!(!summary.record_error(&mut state, Path::new("/etc/ssl"), ErrorKind::ReadDir));
+        assert!(state.get(Path::new("/etc/ssl")).is_none());
+        assert_eq!((summary.errors, summary.errors_by_kind[&ErrorKind::Open], summary.failures.len()), (3, 1, 3));
+    }
+}
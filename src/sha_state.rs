@@ -4,27 +4,113 @@
 
 
 use std::path::{PathBuf, Path};
-use sha1::Digest;
+use crate::events::ErrorKind;
+use crate::hasher::HashValue;
 use anyhow::{bail, anyhow, Context, Result};
 use log::{debug, error, info, trace, warn};
 use std::sync::{Arc, RwLock};
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::time::{SystemTime, Duration, Instant};
 use std::fs::File;
-use std::io::{BufRead, BufWriter, Write, BufReader};
+use std::io::{BufRead, BufWriter, Write, BufReader, Read};
 use std::str::FromStr;
 use std::cmp::Ordering;
 use std::ops::Add;
 use serde::{ser, de, Serialize, Deserialize};
+use serde_json::value::RawValue;
+use crate::state_crypt::is_encrypted;
+use crate::state_seal::{Seal, StateKeys};
 
 
+/// what sort of thing an entry is
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileKind {
+    #[default]
+    File,
+    Dir,
+    Symlink,
+    Fifo,
+    Socket,
+    CharDevice,
+    BlockDevice,
+}
+
+impl FileKind {
+    pub fn of(ft: &std::fs::FileType) -> Self {
+        use std::os::unix::fs::FileTypeExt;
+        if ft.is_dir() {
+            FileKind::Dir
+        } else if ft.is_symlink() {
+            FileKind::Symlink
+        } else if ft.is_fifo() {
+            FileKind::Fifo
+        } else if ft.is_socket() {
+            FileKind::Socket
+        } else if ft.is_char_device() {
+            FileKind::CharDevice
+        } else if ft.is_block_device() {
+            FileKind::BlockDevice
+        } else {
+            FileKind::File
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FileKind::File => "file",
+            FileKind::Dir => "dir",
+            FileKind::Symlink => "symlink",
+            FileKind::Fifo => "fifo",
+            FileKind::Socket => "socket",
+            FileKind::CharDevice => "char_device",
+            FileKind::BlockDevice => "block_device",
+        }
+    }
+
+    fn is_file(&self) -> bool {
+        *self == FileKind::File
+    }
+}
+
 #[derive(Debug, Eq, Clone, Serialize, Deserialize)]
 pub struct ShaState {
     path: PathBuf,
-    sha: Digest,
+    sha: HashValue,
     mtime: SystemTime,
+    /// none in states from before sizes were kept
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
     t_deltas: u64,
     sha_deltas: u64,
+    /// permission bits, none in states from before they were kept
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mode: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    uid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    gid: Option<u32>,
+    /// only regular files have a content hash and size
+    #[serde(default, skip_serializing_if = "FileKind::is_file")]
+    kind: FileKind,
+    /// where a symlink points
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target: Option<PathBuf>,
+    /// device numbers of a device node
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rdev: Option<u64>,
+    /// number of hard links to a regular file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nlink: Option<u64>,
+    /// device and inode number of a regular file, to tell hard links of it from copies
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    inode: Option<(u64, u64)>,
+    /// why the file could not be read, when it never has been
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    unreadable: Option<ErrorKind>,
+    /// hash of as much of the file as the prior entry covered, only while comparing append only files
+    #[serde(skip)]
+    prefix: Option<HashValue>,
 }
 
 impl PartialEq for ShaState {
@@ -35,7 +121,7 @@ impl PartialEq for ShaState {
 
 impl PartialOrd for ShaState {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.path.cmp(&other.path))
+        Some(self.cmp(other))
     }
 }
 
@@ -46,7 +132,7 @@ impl Ord for ShaState {
 }
 
 trait ToErr<T> {
-    fn to_err(self: Self) -> Result<T>;
+    fn to_err(self) -> Result<T>;
 }
 
 impl<T> ToErr<T> for Option<T> {
@@ -58,13 +144,6 @@ impl<T> ToErr<T> for Option<T> {
     }
 }
 
-fn digest_from_str(dig_str: &str) -> Result<Digest> {
-    match Digest::from_str(&dig_str) {
-        Err(_) => return Err(anyhow!("unable to convert string to sha1 digest str: \"{}\"", dig_str)),
-        Ok(v) => Ok(v),
-    }
-}
-
 /*
 type ResultSer<T,E> = std::result::Result<T,E>;
 impl Serialize for Digest {
@@ -83,70 +162,250 @@ impl Deserialize for Digest {
 */
 
 impl ShaState {
-    pub fn new(path: PathBuf, sha: Digest, mtime: SystemTime) -> Self {
-        ShaState { path: path, sha: sha, mtime: mtime, t_deltas: 0, sha_deltas: 0 }
+    pub fn new(path: PathBuf, sha: HashValue, mtime: SystemTime, size: u64) -> Self {
+        ShaState { path, sha, mtime, size: Some(size), t_deltas: 0, sha_deltas: 0, mode: None, uid: None, gid: None,
+            kind: FileKind::File, target: None, rdev: None, nlink: None, inode: None, unreadable: None, prefix: None }
+    }
+
+    /// with the permission bits, owner and, for a regular file, link count from `md`
+    pub fn with_meta(mut self, md: &std::fs::Metadata) -> Self {
+        use std::os::unix::fs::MetadataExt;
+        self.mode = Some(md.mode() & 0o7777);
+        self.uid = Some(md.uid());
+        self.gid = Some(md.gid());
+        self.nlink = if md.is_file() { Some(md.nlink()) } else { None };
+        self.inode = if md.is_file() { Some((md.dev(), md.ino())) } else { None };
+        self
+    }
+
+    /// with the hash of the file up to the size of its prior entry
+    pub fn with_prefix(mut self, prefix: Option<HashValue>) -> Self {
+        self.prefix = prefix;
+        self
+    }
+
+    /// an entry for anything but a regular file, from its own (not followed) metadata
+    pub fn special(path: PathBuf, md: &std::fs::Metadata, target: Option<PathBuf>) -> Self {
+        use std::os::unix::fs::MetadataExt;
+        let mut e = ShaState::new(path, HashValue::default(), md.modified().unwrap_or(SystemTime::UNIX_EPOCH), 0).with_meta(md);
+        e.kind = FileKind::of(&md.file_type());
+        e.target = target;
+        if matches!(e.kind, FileKind::CharDevice | FileKind::BlockDevice) {
+            e.rdev = Some(md.rdev());
+        }
+        e
+    }
+
+    /// a file that could not be read, kept so it is not lost track of
+    pub fn unreadable(path: PathBuf, kind: ErrorKind) -> Self {
+        let mut e = ShaState::new(path, HashValue::default(), SystemTime::UNIX_EPOCH, 0);
+        e.unreadable = Some(kind);
+        e
+    }
+
+    /// has no hash because the file has never been read
+    pub fn is_unreadable(&self) -> bool {
+        self.unreadable.is_some()
+    }
+
+    /// an entry only good for looking up `path` in a set
+    fn probe(path: &Path) -> Self {
+        ShaState::new(path.to_path_buf(), HashValue::default(), SystemTime::UNIX_EPOCH, 0)
+    }
+
+    /// the same entry at a new path, for moves
+    pub fn renamed(mut self, path: PathBuf) -> Self {
+        self.path = path;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn sha(&self) -> &HashValue {
+        &self.sha
+    }
+
+    pub fn mtime(&self) -> SystemTime {
+        self.mtime
+    }
+
+    /// none for entries from before sizes were kept
+    pub fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    pub fn mode(&self) -> Option<u32> {
+        self.mode
+    }
+
+    /// uid and gid
+    pub fn owner(&self) -> Option<(u32, u32)> {
+        self.uid.zip(self.gid)
+    }
+
+    pub fn kind(&self) -> FileKind {
+        self.kind
+    }
+
+    /// where a symlink points
+    pub fn target(&self) -> Option<&Path> {
+        self.target.as_deref()
+    }
+
+    /// device numbers of a device node
+    pub fn rdev(&self) -> Option<u64> {
+        self.rdev
+    }
+
+    /// number of hard links to a regular file
+    pub fn nlink(&self) -> Option<u64> {
+        self.nlink
+    }
+
+    /// device and inode number of a regular file
+    pub fn inode(&self) -> Option<(u64, u64)> {
+        self.inode
+    }
+
+    /// hash of the prior entry's length of the file, when it was asked for
+    pub fn prefix(&self) -> Option<&HashValue> {
+        self.prefix.as_ref()
+    }
+
+    /// number of times the content was seen to change
+    pub fn sha_deltas(&self) -> u64 {
+        self.sha_deltas
+    }
+
+    /// number of times the modification time was seen to change
+    pub fn t_deltas(&self) -> u64 {
+        self.t_deltas
     }
 
     fn from_str(s: &str) -> Result<Self> {
         fn inner(s: &str) -> Result<ShaState> {
             let mut v = s.split('\0');
             let path = PathBuf::from(v.next().to_err()?);
-            let sha = digest_from_str(v.next().to_err()?)?;
+            let sha = HashValue::from_str(v.next().to_err()?)?;
 
             let mtime_p = v.next().to_err()?.parse().context("cannot parse mtime number")?;
-            let mtime = SystemTime::UNIX_EPOCH;
-            mtime.add(Duration::from_secs(mtime_p));
+            let mtime = SystemTime::UNIX_EPOCH.add(Duration::from_secs(mtime_p));
 
             let t_deltas = v.next().to_err()?.parse().context("cannot parse time deltas number")?;
             let sha_deltas = v.next().to_err()?.parse().context("cannot parse sha deltas number")?;
 
             Ok(ShaState {
-                path: path,
-                sha: sha,
-                mtime: mtime,
+                path,
+                sha,
+                mtime,
+                size: None,
                 t_deltas,
                 sha_deltas,
+                mode: None,
+                uid: None,
+                gid: None,
+                kind: FileKind::File,
+                target: None,
+                rdev: None,
+                nlink: None,
+                inode: None,
+                unreadable: None,
+                prefix: None,
             })
         }
         let r = inner(s).with_context(|| format!("trying to parse shastate line: \"{}\"", &s))?;
         Ok(r)
     }
     pub fn write(&self, f: &mut dyn Write) -> Result<()> {
-        write!(f, "{}\0{}\0{}\0{}\0{}\n", self.path.display(), self.sha.to_string(), self.mtime.elapsed().unwrap().as_secs(), self.t_deltas, self.sha_deltas)?;
+        writeln!(f, "{}", self)?;
         Ok(())
     }
+}
 
-    pub fn to_string(&self) -> String {
-        return format!("{}\0{}\0{}\0{}\0{}", self.path.display(), self.sha.to_string(), self.mtime.elapsed().unwrap().as_secs(), self.t_deltas, self.sha_deltas);
+impl std::fmt::Display for ShaState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let secs = self.mtime.duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        write!(f, "{}\0{}\0{}\0{}\0{}", self.path.display(), self.sha, secs, self.t_deltas, self.sha_deltas)
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DiffResult {
     Added,
     BothDiff,
     ShaDiff,
     TimeDiff,
+    /// only the mode or owner changed, never given by `add_with_prior`
+    PermsDiff,
     Same,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct ShaSet(BTreeSet<ShaState>);
+///
+/// Every file entry of a state, plus the roots scanned into it
+///
+/// On disk the entries are split into a section per root, each with when it was last
+/// scanned.  Entries under no known root, e.g. from a state written before roots were kept,
+/// go in a last section with a null root.  The old flat array of entries still loads.
+#[derive(Default)]
+pub struct ShaSet {
+    entries: BTreeSet<ShaState>,
+    roots: BTreeMap<PathBuf, SystemTime>,
+}
+
+#[derive(Serialize)]
+struct StateOut {
+    version: u32,
+    seal: Seal,
+    roots: Box<RawValue>,
+}
+
+#[derive(Serialize)]
+struct SectionOut<'a> {
+    root: Option<&'a Path>,
+    scanned: Option<SystemTime>,
+    entries: Vec<&'a ShaState>,
+}
+
+#[derive(Deserialize)]
+struct StateIn {
+    seal: Option<Seal>,
+    roots: Box<RawValue>,
+}
+
+#[derive(Deserialize)]
+struct SectionIn {
+    root: Option<PathBuf>,
+    scanned: Option<SystemTime>,
+    entries: Vec<ShaState>,
+}
 
 impl ShaSet {
-    pub(crate) fn new(path: &PathBuf) -> Result<Self> {
+    pub fn new(path: &PathBuf, keys: &StateKeys) -> Result<Self> {
         let now = SystemTime::now();
 
-        let f_h = match File::open(&path) {
+        let f_h = match File::open(path) {
             Err(e) => {
                 warn!("There is no initial state file at \"{}\", so going with an initial empty one. {}", path.display(), e);
-                return Ok(ShaSet(BTreeSet::new()));
+                return Ok(ShaSet::default());
             }
             Ok(f) => f,
         };
         let start = Instant::now();
-        let buf = BufReader::new(f_h);
-        let set: ShaSet = serde_json::from_reader(buf)?;
+        let mut r = BufReader::new(f_h);
+        let encrypted = is_encrypted(r.fill_buf()?);
+        let set = match (encrypted, keys.encryption()) {
+            (true, Some(cipher)) => {
+                let mut data = vec![];
+                r.read_to_end(&mut data)?;
+                cipher.decrypt(&data, path).and_then(|plain| ShaSet::read(&plain[..], keys, path))
+            }
+            (true, None) => Err(anyhow!("it is encrypted, give --state-key or --passphrase-file")),
+            (false, Some(_)) => Err(anyhow!("it is not encrypted but a state key or passphrase was given - \
+                                             it may have been replaced, run rekey to encrypt a plain state")),
+            (false, None) => ShaSet::read(r, keys, path),
+        }.with_context(|| format!("cannot load state file \"{}\"", path.display()))?;
         info!("read state file: \"{}\" in {:.3} secs", path.display(), start.elapsed().as_secs_f64());
         // let mut de = serde_json::Deserializer::from_reader(&f_h);
         //
@@ -155,40 +414,153 @@ impl ShaSet {
         Ok(set)
     }
 
-    pub fn add(&mut self, mut e: ShaState) -> Result<DiffResult> {
-        let mut res = Ok(DiffResult::Added);
-        match self.0.take(&e) {
-            Some(mut v) => {
-                match (v.sha == e.sha, v.mtime == e.mtime) {
-                    (true, true) => res = Ok(DiffResult::Same),
+    /// a state in either the sectioned or the old flat layout, its seal checked against `keys`
+    pub fn read<R: BufRead>(mut r: R, keys: &StateKeys, what: &Path) -> Result<Self> {
+        let flat = loop {
+            let buf = r.fill_buf()?;
+            match buf.iter().position(|b| !b.is_ascii_whitespace()) {
+                Some(i) => {
+                    let flat = buf[i] == b'[';
+                    break flat;
+                }
+                None if buf.is_empty() => bail!("state is empty"),
+                None => {
+                    let n = buf.len();
+                    r.consume(n);
+                }
+            }
+        };
+        if flat {
+            keys.verify(&[], None, what)?;
+            return Ok(ShaSet { entries: serde_json::from_reader(r)?, roots: BTreeMap::new() });
+        }
+        let st: StateIn = serde_json::from_reader(r)?;
+        keys.verify(st.roots.get().as_bytes(), st.seal.as_ref(), what)?;
+        let sections: Vec<SectionIn> = serde_json::from_str(st.roots.get())?;
+        let mut set = ShaSet::default();
+        for sec in sections {
+            if let (Some(root), Some(t)) = (sec.root, sec.scanned) {
+                set.roots.insert(root, t);
+            }
+            set.entries.extend(sec.entries);
+        }
+        Ok(set)
+    }
+
+    /// write as sections, each entry going under the deepest root holding it, sealed with `keys`
+    pub fn write<W: Write>(&self, w: W, keys: &StateKeys) -> Result<()> {
+        let mut sections: BTreeMap<Option<&Path>, Vec<&ShaState>> = BTreeMap::new();
+        for r in self.roots.keys() {
+            sections.insert(Some(r.as_path()), vec![]);
+        }
+        for e in &self.entries {
+            sections.entry(self.root_of(&e.path)).or_default().push(e);
+        }
+        let mut roots: Vec<SectionOut> = sections.into_iter()
+            .map(|(root, entries)| SectionOut { root, scanned: root.and_then(|r| self.roots.get(r).copied()), entries })
+            .collect();
+        // None sorts first, but the leftovers read better at the end
+        if roots.first().is_some_and(|s| s.root.is_none()) {
+            roots.rotate_left(1);
+        }
+        let roots = serde_json::to_string_pretty(&roots)?;
+        let seal = keys.seal(roots.as_bytes());
+        serde_json::to_writer_pretty(w, &StateOut { version: 2, seal, roots: RawValue::from_string(roots)? })?;
+        Ok(())
+    }
+
+    /// the deepest known root holding `p`
+    pub fn root_of(&self, p: &Path) -> Option<&Path> {
+        self.roots.keys().filter(|r| p.starts_with(r)).max_by_key(|r| r.components().count()).map(|r| r.as_path())
+    }
+
+    /// note that `root` was scanned at `when`
+    pub fn scanned(&mut self, root: &Path, when: SystemTime) {
+        self.roots.insert(root.to_path_buf(), when);
+    }
+
+    /// roots scanned into this state and when they were last scanned
+    pub fn roots(&self) -> impl Iterator<Item = (&Path, SystemTime)> {
+        self.roots.iter().map(|(r, t)| (r.as_path(), *t))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ShaState> {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// sum of file sizes as of the last run - 0 for state written before sizes were kept
+    pub fn total_size(&self) -> u64 {
+        self.entries.iter().filter_map(|e| e.size).sum()
+    }
+
+    pub fn add(&mut self, e: ShaState) -> Result<DiffResult> {
+        Ok(self.add_with_prior(e)?.0)
+    }
+
+    /// like `add` but also hands back the entry it replaced
+    pub fn add_with_prior(&mut self, mut e: ShaState) -> Result<(DiffResult, Option<ShaState>)> {
+        match self.entries.take(&e) {
+            // a directory's mtime moves with what is in it, only a new type or target is a change
+            Some(v) if v.kind != FileKind::File || e.kind != FileKind::File => {
+                let res = if v.kind == e.kind && v.target == e.target { DiffResult::Same } else { DiffResult::ShaDiff };
+                self.entries.insert(e);
+                Ok((res, Some(v)))
+            }
+            Some(v) => {
+                let res = match (v.sha == e.sha, v.mtime == e.mtime) {
+                    (true, true) => DiffResult::Same,
                     (false, false) => {
                         e.sha_deltas = v.sha_deltas + 1;
                         e.t_deltas = v.t_deltas + 1;
-                        res = Ok(DiffResult::BothDiff)
+                        DiffResult::BothDiff
                     }
                     (true, false) => {
                         e.t_deltas = v.t_deltas + 1;
-                        res = Ok(DiffResult::TimeDiff)
+                        DiffResult::TimeDiff
                     }
                     (false, true) => {
                         e.sha_deltas = v.sha_deltas + 1;
-                        res = Ok(DiffResult::ShaDiff)
+                        DiffResult::ShaDiff
                     }
-                }
-                self.0.insert(e);
-                return res;
+                };
+                self.entries.insert(e);
+                Ok((res, Some(v)))
             }
             None => {
-                self.0.insert(e);
-                return Ok(DiffResult::Added);
+                self.entries.insert(e);
+                Ok((DiffResult::Added, None))
             }
         }
     }
 
+    pub fn get(&self, path: &Path) -> Option<&ShaState> {
+        self.entries.get(&ShaState::probe(path))
+    }
+
+    /// paths of all entries at or under `dir`
+    pub fn paths_under(&self, dir: &Path) -> Vec<PathBuf> {
+        self.entries.range(ShaState::probe(dir)..)
+            .take_while(|e| e.path.starts_with(dir))
+            .map(|e| e.path.clone())
+            .collect()
+    }
+
+    pub fn remove(&mut self, path: &Path) -> Option<ShaState> {
+        self.entries.take(&ShaState::probe(path))
+    }
+
     fn entries_from(path: &Path, set: &mut BTreeSet<ShaState>) -> Result<()> {
         let now = SystemTime::now();
 
-        let f_h = match File::open(&path) {
+        let f_h = match File::open(path) {
             Err(e) => {
                 warn!("There is no initial state file at \"{}\", so going with an initial empty one. {}", path.display(), e);
                 return Ok(());
@@ -196,25 +568,21 @@ impl ShaSet {
             Ok(f) => f,
         };
         let lines = std::io::BufReader::new(f_h).lines();
-        let mut count = 0;
-        for l in lines {
-            count += 1;
-            let l = l.with_context(|| format!("unable parse data file:{}:{}", &path.display(), count))?;
+        for (count, l) in lines.enumerate() {
+            let l = l.with_context(|| format!("unable parse data file:{}:{}", &path.display(), count + 1))?;
             match ShaState::from_str(&l) {
-                Err(e) => {
-                    error!("skipping a line due to {:?}", e);
-                    ()
-                }
+                Err(e) => error!("skipping a line due to {:?}", e),
                 Ok(t) => {
                     set.insert(t);
-                    ()
                 }
             }
         }
         Ok(())
     }
 
-    pub fn write_entries(&self, path: &PathBuf) -> Result<()> {
+    /// write through a synced temp file renamed over `path`, first keeping up to `keep_backups`
+    /// older states as `path.1` (newest) to `path.N`
+    pub fn write_entries(&self, path: &PathBuf, keep_backups: usize, keys: &StateKeys) -> Result<()> {
         let start = Instant::now();
 
         let mut tmppath = path.clone();
@@ -226,17 +594,186 @@ impl ShaSet {
             let file = File::create(&tmppath)
                 .with_context(|| format!("Unable to create tmpfile: \"{}\" to write tracking data too", &tmppath.display()))?;
             let mut buf = BufWriter::new(&file);
-
-            serde_json::to_writer_pretty(buf, &self)?;
-            // for e in self.0.iter() {
-            //     e.write(&mut buf)?;
-            // }
+            match keys.encryption() {
+                Some(cipher) => {
+                    let mut plain = vec![];
+                    self.write(&mut plain, keys)?;
+                    buf.write_all(&cipher.encrypt(&plain)?)?;
+                }
+                None => self.write(&mut buf, keys)?,
+            }
+            buf.flush()?;
+            drop(buf);
+            file.sync_all().with_context(|| format!("Unable to sync tmpfile: \"{}\"", &tmppath.display()))?;
+        }
+        if keep_backups > 0 && path.exists() {
+            rotate_backups(path, keep_backups)?;
         }
-        std::fs::rename(&tmppath, &path)
+        std::fs::rename(&tmppath, path)
             .with_context(|| format!("Unable to post rename tmp file after writing tracking information: rename \"{}\" to \"{}\"", &tmppath.display(), &path.display()))?;
+        sync_dir(path)?;
         info!("wrote state file: {} in {:.3} secs", path.display(), start.elapsed().as_secs_f64());
         Ok(())
     }
 }
 
+/// the `n`th newest backup of the state at `path`, e.g. `etc.json.2`
+pub fn backup_path(path: &Path, n: usize) -> PathBuf {
+    let mut p = path.as_os_str().to_owned();
+    p.push(format!(".{}", n));
+    p.into()
+}
 
+/// shift `path.1`.. up one, dropping the oldest, and make `path.1` a copy of `path`
+///
+/// `path.1` is a hard link where the filesystem allows, so the current state stays in place
+/// until the rename over it.
+fn rotate_backups(path: &Path, keep: usize) -> Result<()> {
+    for n in (1..keep).rev() {
+        let from = backup_path(path, n);
+        if from.exists() {
+            std::fs::rename(&from, backup_path(path, n + 1))
+                .with_context(|| format!("cannot rotate backup \"{}\"", from.display()))?;
+        }
+    }
+    let first = backup_path(path, 1);
+    let _ = std::fs::remove_file(&first);
+    if std::fs::hard_link(path, &first).is_err() {
+        std::fs::copy(path, &first).with_context(|| format!("cannot back up state to \"{}\"", first.display()))?;
+        File::open(&first)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// make the renames in the directory holding `path` durable
+fn sync_dir(path: &Path) -> Result<()> {
+    let dir = match path.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    };
+    File::open(dir).and_then(|d| d.sync_all())
+        .with_context(|| format!("Unable to sync directory \"{}\"", dir.display()))
+}
+
+/// what tests build states and entries from
+#[cfg(test)]
+pub(crate) mod testing {
+    use std::fs::{Metadata, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, SystemTime};
+
+    use super::{ShaSet, ShaState};
+
+    pub const SHA_A: &str = "2aae6c35c94fcfb415dbe95f408b9ce91ee846ed";
+    pub const SHA_B: &str = "0a4d55a8d778e5022fab701977c5d840bbc486d0";
+    pub const SHA_C: &str = "7c4a8d09ca3762af61e59520943dc26494f8941b";
+    /// the sha1 of nothing
+    pub const EMPTY: &str = "da39a3ee5e6b4b0d3255bfef95601890afd80709";
+    /// the mtime `entry` gives
+    pub const MTIME: u64 = 1600000000;
+
+    /// the bytes a seal covers or a cipher encrypts, as a state with one empty root writes them
+    pub const STATE: &[u8] = br#"{"/etc":{"scanned":1600000000,"entries":[]}}"#;
+
+    /// the name errors give the state
+    pub fn what() -> &'static Path {
+        Path::new("state.json")
+    }
+
+    pub fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    /// a file as a scan records it, all with the same mtime
+    pub fn entry(path: &str, sha: &str, size: u64) -> ShaState {
+        ShaState::new(PathBuf::from(path), sha.parse().unwrap(), at(MTIME), size)
+    }
+
+    /// a directory of its own for a test's files, removed when dropped
+    pub struct Scratch(PathBuf);
+
+    impl Scratch {
+        pub fn new() -> Self {
+            static N: AtomicUsize = AtomicUsize::new(0);
+            let dir = std::env::temp_dir().join(format!("shafiles-test-{}-{}", std::process::id(), N.fetch_add(1, Ordering::Relaxed)));
+            std::fs::create_dir(&dir).unwrap();
+            Scratch(dir)
+        }
+
+        pub fn path(&self, name: &str) -> PathBuf {
+            self.0.join(name)
+        }
+
+        /// `name` written with `data`
+        pub fn file(&self, name: &str, data: &[u8]) -> PathBuf {
+            let path = self.path(name);
+            std::fs::write(&path, data).unwrap();
+            path
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// the metadata of whatever `make` creates at the path it is given
+    pub fn stat(make: impl FnOnce(&Path)) -> Metadata {
+        let dir = Scratch::new();
+        let path = dir.path("x");
+        make(&path);
+        std::fs::symlink_metadata(&path).unwrap()
+    }
+
+    /// the metadata of a file with permission bits `mode`
+    pub fn file_mode(mode: u32) -> Metadata {
+        stat(|p| {
+            std::fs::write(p, b"").unwrap();
+            std::fs::set_permissions(p, Permissions::from_mode(mode)).unwrap();
+        })
+    }
+
+    pub fn set(entries: impl IntoIterator<Item = ShaState>) -> ShaSet {
+        let mut set = ShaSet::default();
+        for e in entries {
+            set.add(e).unwrap();
+        }
+        set
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::{at, stat, MTIME};
+    use super::*;
+
+    /// /d as a directory last changed at `secs`
+    fn dir(secs: u64) -> ShaState {
+        ShaState::special(PathBuf::from("/d"), &stat(|p| {
+            std::fs::create_dir(p).unwrap();
+            File::open(p).unwrap().set_modified(at(secs)).unwrap();
+        }), None)
+    }
+
+    #[test]
+    fn dir_mtime_is_not_a_change() {
+        let mut set = ShaSet::default();
+        set.add(dir(MTIME)).unwrap();
+        assert_eq!(set.add(dir(MTIME + 1)).unwrap(), DiffResult::Same);
+        let e = set.get(Path::new("/d")).unwrap();
+        assert_eq!((e.t_deltas, e.sha_deltas), (0, 0));
+    }
+
+    #[test]
+    fn dir_turned_symlink_is_a_change() {
+        let mut set = ShaSet::default();
+        set.add(dir(MTIME)).unwrap();
+        let md = stat(|p| std::os::unix::fs::symlink("/e", p).unwrap());
+        let link = ShaState::special(PathBuf::from("/d"), &md, Some(PathBuf::from("/e")));
+        assert_eq!(set.add(link).unwrap(), DiffResult::ShaDiff);
+        assert_eq!(set.get(Path::new("/d")).unwrap().sha_deltas, 0);
+    }
+}
@@ -0,0 +1,155 @@
+use std::fs::{File, OpenOptions};
+use std::io::{LineWriter, Write};
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::process::Command;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::events::{event_json, Event, EventSink, LogSink};
+use crate::hooks::{HookSink, Hooks};
+use crate::policy::Severity;
+
+///
+/// A sink as given on the command line
+///
+/// One of `log`, `jsonl:PATH`, `syslog`, `syslog:SOCKET` or `exec:COMMAND`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SinkSpec {
+    Log,
+    Jsonl(PathBuf),
+    Syslog(PathBuf),
+    Exec(String),
+}
+
+impl FromStr for SinkSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (kind, arg) = match s.split_once(':') {
+            Some((k, a)) => (k, Some(a)),
+            None => (s, None),
+        };
+        match (kind, arg) {
+            ("log", None) => Ok(SinkSpec::Log),
+            ("jsonl", Some(p)) if !p.is_empty() => Ok(SinkSpec::Jsonl(PathBuf::from(p))),
+            ("syslog", None) => Ok(SinkSpec::Syslog(PathBuf::from("/dev/log"))),
+            ("syslog", Some(p)) if !p.is_empty() => Ok(SinkSpec::Syslog(PathBuf::from(p))),
+            ("exec", Some(c)) if !c.is_empty() => Ok(SinkSpec::Exec(c.to_string())),
+            _ => Err(anyhow!("bad sink \"{}\", expected log, jsonl:PATH, syslog[:SOCKET] or exec:COMMAND", s)),
+        }
+    }
+}
+
+impl SinkSpec {
+    /// the sink to send events to, exec commands running like on change hooks under `hooks`
+    pub fn open(&self, hooks: &Hooks) -> Result<Box<dyn EventSink>> {
+        Ok(match self {
+            SinkSpec::Log => Box::new(LogSink),
+            SinkSpec::Jsonl(p) => Box::new(JsonlSink::open(p)?),
+            SinkSpec::Syslog(p) => Box::new(SyslogSink::open(p)?),
+            SinkSpec::Exec(c) => Box::new(HookSink::new(c, hooks)),
+        })
+    }
+}
+
+/// one JSON object per line appended to a file, written out line by line so a tail sees it
+pub struct JsonlSink {
+    out: LineWriter<File>,
+}
+
+impl JsonlSink {
+    pub fn open(path: &PathBuf) -> Result<Self> {
+        let f = OpenOptions::new().create(true).append(true).open(path)
+            .with_context(|| format!("cannot open event file \"{}\"", path.display()))?;
+        Ok(JsonlSink { out: LineWriter::new(f) })
+    }
+}
+
+impl EventSink for JsonlSink {
+    fn event(&mut self, ev: &Event, severity: Severity) -> Result<()> {
+        serde_json::to_writer(&mut self.out, &event_json(ev, severity))?;
+        self.out.write_all(b"\n")?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+///
+/// RFC 3164 messages to the local syslog socket
+///
+/// Facility is user, severity is warning for changes, alert for alerts and err for errors.
+/// The message body is the event as JSON.
+pub struct SyslogSink {
+    sock: UnixDatagram,
+    tag: String,
+}
+
+impl SyslogSink {
+    pub fn open(path: &PathBuf) -> Result<Self> {
+        let sock = UnixDatagram::unbound()?;
+        sock.connect(path).with_context(|| format!("cannot connect to syslog socket \"{}\"", path.display()))?;
+        Ok(SyslogSink { sock, tag: format!("shafiles[{}]", std::process::id()) })
+    }
+}
+
+impl EventSink for SyslogSink {
+    fn event(&mut self, ev: &Event, severity: Severity) -> Result<()> {
+        const USER: u8 = 1;
+        let level = match (ev, severity) {
+            (_, Severity::Alert) => 1,
+            (Event::Error { .. }, _) => 3,
+            _ => 4,
+        };
+        let msg = format!("<{}>{}: {}", USER * 8 + level, self.tag, event_json(ev, severity));
+        self.sock.send(msg.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// a `sh -c` command with the event's environment variables set
+pub fn event_command(cmd: &str, ev: &Event, severity: Severity) -> Command {
+    let mut c = Command::new("sh");
+    c.arg("-c").arg(cmd)
+        .env("SHAFILES_EVENT", ev.name())
+        .env("SHAFILES_SEVERITY", severity.name())
+        .env("SHAFILES_PATH", ev.path())
+        .env("SHAFILES_OLD_SHA", ev.old_sha().map(|s| s.as_str()).unwrap_or(""))
+        .env("SHAFILES_NEW_SHA", ev.new_sha().map(|s| s.as_str()).unwrap_or(""));
+    c
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sink_specs() {
+        assert_eq!("log".parse::<SinkSpec>().unwrap(), SinkSpec::Log);
+        assert_eq!("jsonl:/var/log/shafiles.jsonl".parse::<SinkSpec>().unwrap(),
+                   SinkSpec::Jsonl(PathBuf::from("/var/log/shafiles.jsonl")));
+        assert_eq!("syslog".parse::<SinkSpec>().unwrap(), SinkSpec::Syslog(PathBuf::from("/dev/log")));
+        assert_eq!("syslog:/run/log".parse::<SinkSpec>().unwrap(), SinkSpec::Syslog(PathBuf::from("/run/log")));
+        // only the first colon splits, the command keeps the rest
+        assert_eq!("exec:logger -t x:y".parse::<SinkSpec>().unwrap(), SinkSpec::Exec("logger -t x:y".to_string()));
+        for bad in ["", "log:x", "jsonl", "jsonl:", "exec", "exec:", "syslog:", "mail:root"] {
+            assert!(bad.parse::<SinkSpec>().is_err(), "{:?} parsed", bad);
+        }
+    }
+
+    #[test]
+    fn exec_sink_failures_are_kept() {
+        let mut sink = SinkSpec::Exec("exit 3".to_string()).open(&Hooks::default()).unwrap();
+        let failures = sink.failures().expect("exec sinks collect failures");
+        let ev = Event::Added { path: PathBuf::from("/etc/passwd"), sha: crate::sha_state::testing::SHA_A.parse().unwrap(), size: 1 };
+        sink.event(&ev, Severity::Info).unwrap();
+        sink.flush().unwrap();
+        assert_eq!(failures.lock().unwrap().len(), 1);
+        assert!(SinkSpec::Log.open(&Hooks::default()).unwrap().failures().is_none());
+    }
+}
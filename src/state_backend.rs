@@ -0,0 +1,229 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use log::{info, warn};
+
+use crate::baseline::{self, Selection};
+use crate::events::Event;
+use crate::lock_file::LockFile;
+use crate::sha_state::{backup_path, ShaSet};
+use crate::state_seal::StateKeys;
+
+///
+/// Where a scan loads the prior state from and saves the new one to
+///
+/// The state is loaded once before the scan starts and saved once after it finishes, with
+/// `lock` taken before the load and held until the backend is dropped or unlocked.
+pub trait StateBackend: Send {
+    /// keep other runs off this state
+    fn lock(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// let another run at the state, e.g. while a watcher hands it to a rescan
+    fn unlock(&mut self) {}
+
+    fn load(&mut self) -> Result<ShaSet>;
+    fn save(&mut self, set: &ShaSet) -> Result<()>;
+    /// something to put in log lines, e.g. the file path
+    fn describe(&self) -> String;
+}
+
+///
+/// The classic state: one pretty printed JSON file, written to a synced temp file and renamed
+///
+/// Locked with a `.lock` file next to it, e.g. `etc.json.lock`.  Older states can be kept as
+/// `etc.json.1` (newest) to `etc.json.N`.  Each save is sealed with a checksum, and an HMAC
+/// or signature when keys are given, which loading checks.  If the state cannot be read,
+/// loading fails naming the newest backup that can, which `from_backup` then loads instead.
+///
+/// With a baseline, runs are compared against it instead of the state, and it only changes
+/// through `accept`.  The state is still written every run and holds what was last seen.  A
+/// missing baseline is made from the first run's state.
+///
+/// When keys are given a missing state or baseline fails to load, as deleting it would
+/// otherwise get around the seal, unless `init` says this is the first run.
+pub struct JsonFile {
+    pub path: PathBuf,
+    /// how long to wait for another run's lock, None to fail at once
+    pub wait_lock: Option<Duration>,
+    /// older states kept on each save
+    pub keep_backups: usize,
+    /// load the newest good backup when the state is corrupt
+    pub from_backup: bool,
+    /// what the state is sealed with
+    pub keys: StateKeys,
+    /// the approved state runs are compared against
+    pub baseline: Option<PathBuf>,
+    /// start from nothing when the state is missing, even though keys are given
+    pub init: bool,
+    /// there was no baseline, so the next save makes one
+    new_baseline: bool,
+    /// the state on disk is corrupt, so the next save must not rotate it into the backups
+    corrupt: bool,
+    lock: Option<LockFile>,
+}
+
+impl JsonFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        JsonFile { path: path.into(), wait_lock: None, keep_backups: 0, from_backup: false, keys: StateKeys::default(),
+                   baseline: None, init: false, new_baseline: false, corrupt: false, lock: None }
+    }
+
+    pub fn keep_backups(mut self, n: usize) -> Self {
+        self.keep_backups = n;
+        self
+    }
+
+    pub fn keys(mut self, keys: StateKeys) -> Self {
+        self.keys = keys;
+        self
+    }
+
+    pub fn from_backup(mut self, yes: bool) -> Self {
+        self.from_backup = yes;
+        self
+    }
+
+    pub fn baseline(mut self, path: impl Into<Option<PathBuf>>) -> Self {
+        self.baseline = path.into();
+        self
+    }
+
+    pub fn init(mut self, yes: bool) -> Self {
+        self.init = yes;
+        self
+    }
+
+    pub fn wait_lock(mut self, wait: impl Into<Option<Duration>>) -> Self {
+        self.wait_lock = wait.into();
+        self
+    }
+
+    /// rewrite the state, the baseline and their backups under `keys`, which are used from then on
+    pub fn rekey(&mut self, keys: StateKeys) -> Result<()> {
+        if !self.path.exists() {
+            bail!("there is no state at \"{}\" to rekey", self.path.display());
+        }
+        self.lock()?;
+        for path in std::iter::once(&self.path).chain(self.baseline.iter().filter(|b| b.exists())) {
+            ShaSet::new(path, &self.keys)?.write_entries(path, 0, &keys)?;
+            for p in (1..).map(|n| backup_path(path, n)).take_while(|p| p.exists()) {
+                match ShaSet::new(&p, &self.keys) {
+                    Ok(set) => set.write_entries(&p, 0, &keys)?,
+                    Err(e) => warn!("backup left as it was: {:#}", e),
+                }
+            }
+        }
+        self.keys = keys;
+        Ok(())
+    }
+
+    /// take the selected differences between the state and the baseline into the baseline
+    pub fn accept(&mut self, selection: &Selection) -> Result<Vec<Event>> {
+        let path = self.baseline.clone().ok_or_else(|| anyhow!("no baseline given to accept into"))?;
+        if !path.exists() {
+            bail!("there is no baseline at \"{}\" yet, a scan makes it", path.display());
+        }
+        self.keys.can_seal()?;
+        self.lock()?;
+        let observed = self.read_or_backup(&self.path)?.0;
+        let mut baseline = self.read_or_backup(&path)?.0;
+        let taken = baseline::accept(&mut baseline, &observed, selection);
+        if !taken.is_empty() {
+            baseline.write_entries(&path, self.keep_backups, &self.keys)?;
+        }
+        Ok(taken)
+    }
+
+    /// the state as last saved, without locking since saves replace it whole
+    pub fn read(&self) -> Result<ShaSet> {
+        Ok(self.read_or_backup(&self.path)?.0)
+    }
+
+    /// the set at `path`, or the newest good backup of it with `from_backup`, saying if it was one
+    fn read_or_backup(&self, path: &PathBuf) -> Result<(ShaSet, bool)> {
+        self.check_missing(path)?;
+        let e = match ShaSet::new(path, &self.keys) {
+            Ok(set) => return Ok((set, false)),
+            Err(e) => e,
+        };
+        let good = (1..).map(|n| backup_path(path, n)).take_while(|p| p.exists())
+            .find_map(|p| match ShaSet::new(&p, &self.keys) {
+                Ok(set) => Some((p, set)),
+                Err(e) => {
+                    warn!("backup is no good either: {:#}", e);
+                    None
+                }
+            });
+        match good {
+            None => Err(e),
+            Some((p, set)) if self.from_backup => {
+                info!("\"{}\" is corrupt, starting from backup \"{}\"", path.display(), p.display());
+                Ok((set, true))
+            }
+            Some((p, _)) => bail!("{:#}\nbackup \"{}\" reads fine, rerun with --from-backup to start from it",
+                e, p.display()),
+        }
+    }
+
+    /// fail if `path` is missing while keys are given, unless this is the first run
+    fn check_missing(&self, path: &Path) -> Result<()> {
+        if self.keys.is_keyed() && !self.init && !path.exists() {
+            bail!("there is no state at \"{}\" but keys are given - it may have been deleted, \
+                   pass --init if this is the first run", path.display());
+        }
+        Ok(())
+    }
+
+    pub fn lock_path(&self) -> PathBuf {
+        let mut p = self.path.clone().into_os_string();
+        p.push(".lock");
+        p.into()
+    }
+}
+
+impl StateBackend for JsonFile {
+    fn lock(&mut self) -> Result<()> {
+        if self.lock.is_none() {
+            self.lock = Some(LockFile::acquire(&self.lock_path(), self.wait_lock)?);
+        }
+        Ok(())
+    }
+
+    fn unlock(&mut self) {
+        self.lock = None;
+    }
+
+    fn load(&mut self) -> Result<ShaSet> {
+        self.keys.can_seal()?;
+        match &self.baseline {
+            Some(b) if b.exists() => return Ok(self.read_or_backup(b)?.0),
+            Some(b) => {
+                self.check_missing(b)?;
+                info!("no baseline at \"{}\" yet, this run's state becomes it", b.display());
+                self.new_baseline = true;
+            }
+            None => {}
+        }
+        let (set, corrupt) = self.read_or_backup(&self.path)?;
+        self.corrupt = corrupt;
+        Ok(set)
+    }
+
+    fn save(&mut self, set: &ShaSet) -> Result<()> {
+        let keep = if self.corrupt { 0 } else { self.keep_backups };
+        set.write_entries(&self.path, keep, &self.keys)?;
+        self.corrupt = false;
+        if let Some(b) = self.baseline.as_ref().filter(|_| self.new_baseline) {
+            set.write_entries(b, 0, &self.keys)?;
+            self.new_baseline = false;
+        }
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        self.path.display().to_string()
+    }
+}
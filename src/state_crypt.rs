@@ -0,0 +1,170 @@
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use hmac::Hmac;
+use sha2::Sha256;
+
+use crate::state_seal::read_key32;
+
+/// what an encrypted state starts with, a plain one starts with `{` or `[`
+const MAGIC: &[u8; 8] = b"SHAFENC1";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const HEADER_LEN: usize = MAGIC.len() + 1 + SALT_LEN + NONCE_LEN;
+const PBKDF2_ROUNDS: u32 = 200_000;
+
+///
+/// The secret a state is encrypted with
+///
+/// An encrypted state is `SHAFENC1`, a byte saying which kind of secret, a salt, a nonce and
+/// then the XChaCha20-Poly1305 ciphertext of the usual JSON, with the header as associated
+/// data.  A passphrase is stretched with PBKDF2-SHA256 over the salt, a key file is used as is.
+#[derive(Clone)]
+pub enum StateCipher {
+    Key([u8; 32]),
+    Passphrase(String),
+}
+
+impl StateCipher {
+    /// 32 bytes, raw or as hex, e.g. from `head -c32 /dev/urandom > key`
+    pub fn key_file(path: &Path) -> Result<Self> {
+        read_key32(path, "state key").map(StateCipher::Key)
+    }
+
+    /// the first line of the file
+    pub fn passphrase_file(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("cannot read passphrase \"{}\"", path.display()))?;
+        let pass = text.lines().next().unwrap_or("");
+        if pass.is_empty() {
+            bail!("passphrase \"{}\" is empty", path.display());
+        }
+        Ok(StateCipher::Passphrase(pass.to_string()))
+    }
+
+    fn kind(&self) -> u8 {
+        match self {
+            StateCipher::Key(_) => 0,
+            StateCipher::Passphrase(_) => 1,
+        }
+    }
+
+    fn key(&self, salt: &[u8]) -> [u8; 32] {
+        match self {
+            StateCipher::Key(k) => *k,
+            StateCipher::Passphrase(p) => {
+                let mut k = [0u8; 32];
+                pbkdf2::pbkdf2::<Hmac<Sha256>>(p.as_bytes(), salt, PBKDF2_ROUNDS, &mut k);
+                k
+            }
+        }
+    }
+
+    pub fn encrypt(&self, plain: &[u8]) -> Result<Vec<u8>> {
+        let mut header = Vec::with_capacity(HEADER_LEN + plain.len() + 16);
+        header.extend_from_slice(MAGIC);
+        header.push(self.kind());
+        let mut salt = [0u8; SALT_LEN];
+        let mut nonce = [0u8; NONCE_LEN];
+        getrandom::getrandom(&mut salt).and_then(|_| getrandom::getrandom(&mut nonce))
+            .map_err(|e| anyhow!("no randomness for the state nonce: {}", e))?;
+        header.extend_from_slice(&salt);
+        header.extend_from_slice(&nonce);
+        let cipher = XChaCha20Poly1305::new(&Key::from(self.key(&salt)));
+        let sealed = cipher.encrypt(&XNonce::from(nonce), Payload { msg: plain, aad: &header })
+            .map_err(|_| anyhow!("cannot encrypt state"))?;
+        header.extend_from_slice(&sealed);
+        Ok(header)
+    }
+
+    pub fn decrypt(&self, data: &[u8], what: &Path) -> Result<Vec<u8>> {
+        if data.len() < HEADER_LEN || !is_encrypted(data) {
+            bail!("state \"{}\" is not an encrypted state", what.display());
+        }
+        let (header, sealed) = data.split_at(HEADER_LEN);
+        if header[MAGIC.len()] != self.kind() {
+            bail!("state \"{}\" was encrypted with a {}, not a {}", what.display(),
+                kind_name(header[MAGIC.len()]), kind_name(self.kind()));
+        }
+        let salt = &header[MAGIC.len() + 1..MAGIC.len() + 1 + SALT_LEN];
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce.copy_from_slice(&header[MAGIC.len() + 1 + SALT_LEN..]);
+        let cipher = XChaCha20Poly1305::new(&Key::from(self.key(salt)));
+        cipher.decrypt(&XNonce::from(nonce), Payload { msg: sealed, aad: header })
+            .map_err(|_| anyhow!("cannot decrypt state \"{}\" - wrong key or passphrase, or it was changed", what.display()))
+    }
+}
+
+fn kind_name(kind: u8) -> &'static str {
+    match kind {
+        0 => "key file",
+        1 => "passphrase",
+        _ => "unknown kind of secret",
+    }
+}
+
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sha_state::testing::{what, Scratch, STATE};
+
+    #[test]
+    fn key_round_trip() {
+        let cipher = StateCipher::Key([3; 32]);
+        let data = cipher.encrypt(STATE).unwrap();
+        assert!(is_encrypted(&data));
+        assert!(!data.windows(STATE.len()).any(|w| w == STATE));
+        assert_eq!(cipher.decrypt(&data, what()).unwrap(), STATE);
+        // a fresh nonce each time
+        assert_ne!(cipher.encrypt(STATE).unwrap(), data);
+    }
+
+    #[test]
+    fn passphrase_round_trip() {
+        let cipher = StateCipher::Passphrase("correct horse".into());
+        let data = cipher.encrypt(STATE).unwrap();
+        assert_eq!(cipher.decrypt(&data, what()).unwrap(), STATE);
+    }
+
+    #[test]
+    fn wrong_secret_fails() {
+        let data = StateCipher::Key([3; 32]).encrypt(STATE).unwrap();
+        assert!(StateCipher::Key([4; 32]).decrypt(&data, what()).is_err());
+        let err = StateCipher::Passphrase("correct horse".into()).decrypt(&data, what()).unwrap_err();
+        assert!(err.to_string().contains("key file, not a passphrase"), "{}", err);
+
+        let data = StateCipher::Passphrase("correct horse".into()).encrypt(STATE).unwrap();
+        assert!(StateCipher::Passphrase("battery staple".into()).decrypt(&data, what()).is_err());
+    }
+
+    #[test]
+    fn tampering_fails() {
+        let cipher = StateCipher::Key([3; 32]);
+        let data = cipher.encrypt(STATE).unwrap();
+        for i in [MAGIC.len() + 1, HEADER_LEN - 1, HEADER_LEN, data.len() - 1] {
+            let mut bad = data.clone();
+            bad[i] ^= 1;
+            assert!(cipher.decrypt(&bad, what()).is_err(), "byte {} flipped", i);
+        }
+        assert!(cipher.decrypt(&data[..data.len() - 1], what()).is_err());
+        assert!(cipher.decrypt(STATE, what()).is_err());
+    }
+
+    #[test]
+    fn plain_state_refused_with_a_secret() {
+        use crate::sha_state::ShaSet;
+        use crate::state_seal::StateKeys;
+
+        let dir = Scratch::new();
+        let path = dir.file("state.json", b"[]");
+        ShaSet::new(&path, &StateKeys::default()).unwrap();
+        let refused = ShaSet::new(&path, &StateKeys::default().cipher(Some(StateCipher::Key([3; 32]))));
+        let err = refused.err().expect("a plain state loaded with a state key");
+        assert!(format!("{:#}", err).contains("not encrypted"), "{:#}", err);
+    }
+}
@@ -0,0 +1,247 @@
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer, Verifier};
+use hmac::{Hmac, Mac, NewMac};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::state_crypt::StateCipher;
+
+///
+/// Proof that the entries of a state were written by us and not edited since
+///
+/// Covers the exact bytes of the state's "roots" value.  The sha256 is always there and
+/// catches damage, but anyone able to edit the state can recompute it - the HMAC or the
+/// Ed25519 signature are what stop an attacker without the key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Seal {
+    pub sha256: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hmac_sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ed25519: Option<String>,
+}
+
+///
+/// The keys a state is sealed with and checked against, and optionally encrypted with
+///
+/// When a key is given, a state without that part of the seal fails to load, so stripping
+/// the seal does not get around it.  The seal is taken over the plain text, inside the
+/// encryption.
+///
+/// Signatures are checked with the public verify key, so hosts that only read the state do
+/// not need the secret.  The signing key is only for the run that writes the state, which
+/// checks against its public half when no verify key is given.
+#[derive(Clone, Default)]
+pub struct StateKeys {
+    hmac: Option<Vec<u8>>,
+    signing: Option<[u8; 32]>,
+    verify: Option<PublicKey>,
+    cipher: Option<StateCipher>,
+}
+
+/// 32 bytes, raw or as hex
+pub(crate) fn read_key32(path: &Path, what: &str) -> Result<[u8; 32]> {
+    let raw = std::fs::read(path).with_context(|| format!("cannot read {} \"{}\"", what, path.display()))?;
+    let key = match raw.len() {
+        32 => raw,
+        _ => hex::decode(String::from_utf8_lossy(&raw).trim())
+            .map_err(|e| anyhow!("{} \"{}\" is neither 32 bytes nor hex: {}", what, path.display(), e))?,
+    };
+    if key.len() != 32 {
+        bail!("{} \"{}\" is {} bytes, expected 32", what, path.display(), key.len());
+    }
+    let mut out = [0u8; 32];
+    out.copy_from_slice(&key);
+    Ok(out)
+}
+
+impl StateKeys {
+    pub fn cipher(mut self, cipher: Option<StateCipher>) -> Self {
+        self.cipher = cipher;
+        self
+    }
+
+    /// what the state is encrypted with, if it is
+    pub fn encryption(&self) -> Option<&StateCipher> {
+        self.cipher.as_ref()
+    }
+
+    /// the HMAC key is the whole of the file, as is
+    pub fn hmac_key_file(mut self, path: &Path) -> Result<Self> {
+        let key = std::fs::read(path).with_context(|| format!("cannot read hmac key \"{}\"", path.display()))?;
+        if key.is_empty() {
+            bail!("hmac key \"{}\" is empty", path.display());
+        }
+        self.hmac = Some(key);
+        Ok(self)
+    }
+
+    /// the Ed25519 secret key is 32 bytes, raw or as hex, e.g. from `head -c32 /dev/urandom | xxd -p -c32`
+    pub fn signing_key_file(mut self, path: &Path) -> Result<Self> {
+        let seed = read_key32(path, "signing key")?;
+        let secret = SecretKey::from_bytes(&seed).map_err(|e| anyhow!("bad signing key \"{}\": {}", path.display(), e))?;
+        info!("signing key \"{}\" has the public key {}, give it to --verify-key where the state is only checked",
+            path.display(), hex::encode(PublicKey::from(&secret).as_bytes()));
+        self.signing = Some(seed);
+        Ok(self)
+    }
+
+    /// the Ed25519 public key is 32 bytes, raw or as hex, as logged when the signing key is loaded
+    pub fn verify_key_file(mut self, path: &Path) -> Result<Self> {
+        let key = read_key32(path, "verify key")?;
+        let public = PublicKey::from_bytes(&key).map_err(|e| anyhow!("bad verify key \"{}\": {}", path.display(), e))?;
+        self.verify = Some(public);
+        Ok(self)
+    }
+
+    /// some key is given, so a missing state is suspect
+    pub fn is_keyed(&self) -> bool {
+        self.hmac.is_some() || self.signing.is_some() || self.verify.is_some() || self.cipher.is_some()
+    }
+
+    /// fail if a state written with these keys would not load with them
+    pub fn can_seal(&self) -> Result<()> {
+        if self.verify.is_some() && self.signing.is_none() {
+            bail!("a verify key can only check the state, give the signing key to runs that write it");
+        }
+        Ok(())
+    }
+
+    fn keypair(&self) -> Option<Keypair> {
+        let secret = SecretKey::from_bytes(self.signing.as_ref()?).ok()?;
+        let public = PublicKey::from(&secret);
+        Some(Keypair { secret, public })
+    }
+
+    /// the key signatures are checked with
+    fn public_key(&self) -> Option<PublicKey> {
+        self.verify.or_else(|| self.keypair().map(|k| k.public))
+    }
+
+    fn mac(&self, data: &[u8]) -> Option<Hmac<Sha256>> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.hmac.as_ref()?).ok()?;
+        mac.update(data);
+        Some(mac)
+    }
+
+    pub fn seal(&self, data: &[u8]) -> Seal {
+        Seal {
+            sha256: hex::encode(Sha256::digest(data)),
+            hmac_sha256: self.mac(data).map(|m| hex::encode(m.finalize().into_bytes())),
+            ed25519: self.keypair().map(|k| hex::encode(k.sign(data).to_bytes())),
+        }
+    }
+
+    /// fail unless `seal` matches `data` and carries whatever our keys call for
+    pub fn verify(&self, data: &[u8], seal: Option<&Seal>, what: &Path) -> Result<()> {
+        let seal = match seal {
+            Some(s) => s,
+            None if self.hmac.is_some() || self.public_key().is_some() =>
+                bail!("state \"{}\" is not sealed but a key was given - it may have been replaced", what.display()),
+            None => {
+                warn!("state \"{}\" has no checksum, it will get one when next written", what.display());
+                return Ok(());
+            }
+        };
+        if hex::encode(Sha256::digest(data)) != seal.sha256 {
+            bail!("state \"{}\" does not match its checksum - it was changed or damaged since it was written", what.display());
+        }
+        if let Some(mac) = self.mac(data) {
+            let tag = seal.hmac_sha256.as_ref()
+                .ok_or_else(|| anyhow!("state \"{}\" has no hmac but an hmac key was given", what.display()))?;
+            let tag = hex::decode(tag).map_err(|e| anyhow!("state \"{}\" has a bad hmac: {}", what.display(), e))?;
+            mac.verify(&tag).map_err(|_| anyhow!("state \"{}\" fails its hmac - it was changed since it was written", what.display()))?;
+        }
+        if let Some(public) = self.public_key() {
+            let sig = seal.ed25519.as_ref()
+                .ok_or_else(|| anyhow!("state \"{}\" has no signature but a signing or verify key was given", what.display()))?;
+            let sig = hex::decode(sig).ok().and_then(|s| Signature::from_bytes(&s).ok())
+                .ok_or_else(|| anyhow!("state \"{}\" has a bad signature", what.display()))?;
+            public.verify(data, &sig)
+                .map_err(|_| anyhow!("state \"{}\" fails its signature - it was changed since it was written", what.display()))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sha_state::testing::{what, STATE};
+
+    fn hmac() -> StateKeys {
+        StateKeys { hmac: Some(b"a secret".to_vec()), ..StateKeys::default() }
+    }
+
+    fn signing(seed: u8) -> StateKeys {
+        StateKeys { signing: Some([seed; 32]), ..StateKeys::default() }
+    }
+
+    /// only the public half of the key `signing(seed)` signs with
+    fn verifying(seed: u8) -> StateKeys {
+        StateKeys { verify: signing(seed).public_key(), ..StateKeys::default() }
+    }
+
+    #[test]
+    fn checksum_only_catches_damage() {
+        let keys = StateKeys::default();
+        let seal = keys.seal(STATE);
+        assert!(seal.hmac_sha256.is_none() && seal.ed25519.is_none());
+        keys.verify(STATE, Some(&seal), what()).unwrap();
+        assert!(keys.verify(b"{}", Some(&seal), what()).is_err());
+        // an unsealed state is read without keys, it gets a seal when next written
+        keys.verify(STATE, None, what()).unwrap();
+    }
+
+    #[test]
+    fn hmac_rejects_a_recomputed_checksum() {
+        let seal = hmac().seal(STATE);
+        hmac().verify(STATE, Some(&seal), what()).unwrap();
+
+        let forged = Seal { sha256: hex::encode(Sha256::digest(b"{}")), ..seal.clone() };
+        assert!(hmac().verify(b"{}", Some(&forged), what()).is_err());
+        let stripped = Seal { hmac_sha256: None, ..seal.clone() };
+        assert!(hmac().verify(STATE, Some(&stripped), what()).is_err());
+        let other = StateKeys { hmac: Some(b"another secret".to_vec()), ..StateKeys::default() };
+        assert!(other.verify(STATE, Some(&seal), what()).is_err());
+        assert!(hmac().verify(STATE, None, what()).is_err());
+    }
+
+    #[test]
+    fn signature_rejects_a_recomputed_checksum() {
+        let seal = signing(7).seal(STATE);
+        signing(7).verify(STATE, Some(&seal), what()).unwrap();
+
+        assert!(signing(8).verify(STATE, Some(&seal), what()).is_err());
+        let forged = Seal { sha256: hex::encode(Sha256::digest(b"{}")), ..seal.clone() };
+        assert!(signing(7).verify(b"{}", Some(&forged), what()).is_err());
+        let stripped = Seal { ed25519: None, ..seal.clone() };
+        assert!(signing(7).verify(STATE, Some(&stripped), what()).is_err());
+        let garbled = Seal { ed25519: Some("not hex".into()), ..seal };
+        assert!(signing(7).verify(STATE, Some(&garbled), what()).is_err());
+        assert!(signing(7).verify(STATE, None, what()).is_err());
+    }
+
+    #[test]
+    fn signature_checks_with_the_public_key_alone() {
+        let seal = signing(7).seal(STATE);
+        verifying(7).verify(STATE, Some(&seal), what()).unwrap();
+
+        assert!(verifying(8).verify(STATE, Some(&seal), what()).is_err());
+        let forged = Seal { sha256: hex::encode(Sha256::digest(b"{}")), ..seal.clone() };
+        assert!(verifying(7).verify(b"{}", Some(&forged), what()).is_err());
+        let stripped = Seal { ed25519: None, ..seal };
+        assert!(verifying(7).verify(STATE, Some(&stripped), what()).is_err());
+        assert!(verifying(7).verify(STATE, None, what()).is_err());
+    }
+
+    #[test]
+    fn verify_key_cannot_seal() {
+        assert!(verifying(7).can_seal().is_err());
+        signing(7).can_seal().unwrap();
+        StateKeys { verify: signing(7).public_key(), ..signing(7) }.can_seal().unwrap();
+    }
+}
@@ -0,0 +1,148 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use log::{debug, info};
+
+use crate::device_pool::{dev_name, devices_under};
+
+/// number of cpus we may run on, 1 if it cannot be found
+pub fn cpus() -> usize {
+    std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+}
+
+///
+/// Is the block device behind `dev` a spinning disk
+///
+/// Reads /sys/dev/block/MAJ:MIN/queue/rotational, walking up from a partition to its disk.
+/// None when it cannot be told, e.g. network or virtual filesystems.
+pub fn is_rotational(dev: u64) -> Option<bool> {
+    let sys = std::fs::canonicalize(format!("/sys/dev/block/{}", dev_name(dev))).ok()?;
+    let mut dir: Option<&Path> = Some(&sys);
+    while let Some(d) = dir {
+        let f = d.join("queue/rotational");
+        if let Ok(s) = std::fs::read_to_string(&f) {
+            debug!("{} says {}", f.display(), s.trim());
+            return Some(s.trim() == "1");
+        }
+        if d.file_name().is_none_or(|n| n == "block") {
+            break;
+        }
+        dir = d.parent();
+    }
+    None
+}
+
+/// sha threads to use for one device when none are given
+pub fn default_sha_threads(rotational: Option<bool>) -> usize {
+    match rotational {
+        // one reader at a time keeps a spinning disk streaming instead of seeking
+        Some(true) => 1,
+        _ => cpus(),
+    }
+}
+
+/// directory threads to use when none are given
+pub fn default_dir_threads(rotational: Option<bool>) -> usize {
+    match rotational {
+        Some(true) => 2,
+        _ => cpus().clamp(2, 8),
+    }
+}
+
+fn rotational_str(rotational: Option<bool>) -> &'static str {
+    match rotational {
+        Some(true) => "rotational",
+        Some(false) => "non-rotational",
+        None => "unknown",
+    }
+}
+
+///
+/// The thread counts a run starts with and where they came from
+///
+/// Kept around so the run summary can say what was picked and why.
+#[derive(Debug, Clone)]
+pub struct ThreadPlan {
+    pub dir_threads: usize,
+    pub sha_threads: usize,
+    pub auto_dir: bool,
+    pub auto_sha: bool,
+    pub rotational: Option<bool>,
+}
+
+/// storage of the devices under `roots` taken together: non-rotational if any is, since a
+/// spinning disk's defaults would starve it, and unknown only if none can be told
+fn rotational_of(roots: &[PathBuf]) -> Option<bool> {
+    let known: Vec<bool> = devices_under(roots).into_iter().filter_map(is_rotational).collect();
+    match known.is_empty() {
+        true => None,
+        false => Some(known.iter().all(|&r| r)),
+    }
+}
+
+impl ThreadPlan {
+    /// defaults for what is not given, from the storage of all the roots and what is mounted under them
+    pub fn new(roots: &[PathBuf], dir_threads: Option<usize>, sha_threads: Option<usize>) -> Result<Self> {
+        let rotational = rotational_of(roots);
+        let plan = ThreadPlan {
+            dir_threads: dir_threads.unwrap_or_else(|| default_dir_threads(rotational)),
+            sha_threads: sha_threads.unwrap_or_else(|| default_sha_threads(rotational)),
+            auto_dir: dir_threads.is_none(),
+            auto_sha: sha_threads.is_none(),
+            rotational,
+        };
+        info!("{}", plan);
+        Ok(plan)
+    }
+}
+
+impl std::fmt::Display for ThreadPlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "threads: dir {}{}  sha {}{}  ({} cpus, root storage {})",
+            self.dir_threads, if self.auto_dir { " (auto)" } else { "" },
+            self.sha_threads, if self.auto_sha { " (auto)" } else { "" },
+            cpus(), rotational_str(self.rotational))
+    }
+}
+
+///
+/// Hill climbing on the number of active sha workers of one pool
+///
+/// Every step compares throughput to the step before.  If it got better keep moving the
+/// same way, otherwise turn around.  The best count seen is kept for the run summary.
+#[derive(Debug)]
+pub struct AutoTuner {
+    max: usize,
+    dir: isize,
+    last_bc: usize,
+    last_rate: f64,
+    pub best_active: usize,
+    pub best_rate: f64,
+}
+
+impl AutoTuner {
+    pub fn new(start: usize, max: usize) -> Self {
+        AutoTuner { max, dir: 1, last_bc: 0, last_rate: 0.0, best_active: start, best_rate: 0.0 }
+    }
+
+    /// given total bytes so far, seconds since the last step and the current active count,
+    /// return the active count to use next
+    pub fn step(&mut self, bc: usize, secs: f64, active: usize) -> usize {
+        let rate = (bc - self.last_bc) as f64 / secs;
+        self.last_bc = bc;
+        if rate > self.best_rate {
+            self.best_rate = rate;
+            self.best_active = active;
+        }
+        // allow 5% noise before deciding it got worse
+        if rate < self.last_rate * 0.95 {
+            self.dir = -self.dir;
+        }
+        self.last_rate = rate;
+        let next = (active as isize + self.dir).clamp(1, self.max as isize) as usize;
+        if next == active {
+            self.dir = -self.dir;
+        }
+        next
+    }
+}
@@ -0,0 +1,769 @@
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr};
+use std::fs;
+use std::io;
+use std::mem::size_of;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use globset::GlobSet;
+use log::{debug, error, info, warn};
+
+use crate::events::{Dispatcher, Event, EventSink, FnSink};
+use crate::hasher::HashAlgo;
+use crate::hooks::{run_on_complete, HookSink, Hooks};
+use crate::policy::Policy;
+use crate::scanner::{build_globs, change_kind, confirm_rot, sha_a_file, Hashed, ScanSummary, Stability};
+use crate::sha_state::{DiffResult, ShaSet, ShaState};
+use crate::state_backend::StateBackend;
+
+/// how long a read of the notify fd waits, and so how quickly a stop is seen
+const POLL: Duration = Duration::from_millis(250);
+
+/// a MOVED_FROM whose MOVED_TO has not turned up by then went out of the tree
+const MOVE_WAIT: Duration = Duration::from_millis(500);
+
+const WATCH_MASK: u32 = libc::IN_CLOSE_WRITE | libc::IN_ATTRIB | libc::IN_CREATE | libc::IN_DELETE
+    | libc::IN_MOVED_FROM | libc::IN_MOVED_TO | libc::IN_ONLYDIR | libc::IN_DONT_FOLLOW;
+
+///
+/// Keeps a state current by watching the tree instead of scanning it
+///
+/// The loaded state is the baseline.  Files are rehashed once they have gone `settle` without
+/// another write, create or move, and the results go to the sinks as the same events a scan
+/// gives.  The state is saved every `save_every` when something changed and once more on stop.
+///
+/// With inotify every directory gets a watch.  If the watch limit is hit, or with fanotify
+/// which only reports writes, the `rescan` function is run every `rescan_every` to catch
+/// what the watches cannot.  The state is saved and unlocked before each rescan, and locked
+/// and reloaded after it.
+pub struct Watcher {
+    top_dir: PathBuf,
+    state: Box<dyn StateBackend>,
+    excludes: Vec<String>,
+    accept_corruption: Vec<String>,
+    hash: HashAlgo,
+    buffer_size: usize,
+    settle: Duration,
+    stability: Stability,
+    follow_symlinks: bool,
+    save_every: Duration,
+    rescan_every: Duration,
+    fanotify: bool,
+    sinks: Vec<Box<dyn EventSink>>,
+    policy: Policy,
+    hooks: Hooks,
+    rescan: Option<Box<dyn FnMut() -> Result<ScanSummary>>>,
+}
+
+impl Watcher {
+    pub fn new(top_dir: impl Into<PathBuf>, state: impl StateBackend + 'static) -> Self {
+        Watcher {
+            top_dir: top_dir.into(),
+            state: Box::new(state),
+            excludes: vec![],
+            accept_corruption: vec![],
+            hash: HashAlgo::Sha1,
+            buffer_size: 1024 * 1024,
+            settle: Duration::from_secs(2),
+            stability: Stability::default(),
+            follow_symlinks: false,
+            save_every: Duration::from_secs(60),
+            rescan_every: Duration::from_secs(3600),
+            fanotify: false,
+            sinks: vec![],
+            policy: Policy::default(),
+            hooks: Hooks::default(),
+            rescan: None,
+        }
+    }
+
+    /// ignore changes to paths matching this glob, e.g. "**/.git"
+    pub fn exclude(mut self, glob: impl Into<String>) -> Self {
+        self.excludes.push(glob.into());
+        self
+    }
+
+    /// take the new hash of corrupt files matching this glob rather than keep reporting them
+    pub fn accept_corruption(mut self, glob: impl Into<String>) -> Self {
+        self.accept_corruption.push(glob.into());
+        self
+    }
+
+    pub fn hash(mut self, algo: HashAlgo) -> Self {
+        self.hash = algo;
+        self
+    }
+
+    pub fn buffer_size(mut self, bytes: usize) -> Self {
+        self.buffer_size = bytes;
+        self
+    }
+
+    /// quiet time a file needs before it is rehashed
+    pub fn settle(mut self, settle: Duration) -> Self {
+        self.settle = settle;
+        self
+    }
+
+    /// retries for files changing as they are read and how long a file must be left alone first
+    pub fn stability(mut self, stability: Stability) -> Self {
+        self.stability = stability;
+        self
+    }
+
+    /// hash what links to files point to, instead of recording the links
+    pub fn follow_symlinks(mut self, on: bool) -> Self {
+        self.follow_symlinks = on;
+        self
+    }
+
+    pub fn save_every(mut self, every: Duration) -> Self {
+        self.save_every = every;
+        self
+    }
+
+    pub fn rescan_every(mut self, every: Duration) -> Self {
+        self.rescan_every = every;
+        self
+    }
+
+    /// watch the whole mount with fanotify rather than each directory with inotify
+    pub fn fanotify(mut self, on: bool) -> Self {
+        self.fanotify = on;
+        self
+    }
+
+    pub fn sink(mut self, sink: Box<dyn EventSink>) -> Self {
+        self.sinks.push(sink);
+        self
+    }
+
+    pub fn on_change(self, f: impl FnMut(&Event) + Send + 'static) -> Self {
+        self.sink(Box::new(FnSink(f)))
+    }
+
+    /// which changes matter where and how much, everything at info without one
+    pub fn policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// on change runs per event, on complete after every save with the changes since the last
+    pub fn hooks(mut self, hooks: Hooks) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    /// a full scan against the same state, typically a `Scanner` run
+    pub fn rescan(mut self, f: impl FnMut() -> Result<ScanSummary> + 'static) -> Self {
+        self.rescan = Some(Box::new(f));
+        self
+    }
+
+    /// watch until `stop` is set, then save the state
+    pub fn run(mut self, stop: &AtomicBool) -> Result<()> {
+        let excludes = build_globs(&self.excludes)?;
+        let accepted = build_globs(&self.accept_corruption)?;
+        self.state.lock()?;
+        let set = self.state.load()?;
+        if let Some(cmd) = &self.hooks.on_change {
+            let hook = HookSink::new(cmd, &self.hooks);
+            self.sinks.push(Box::new(hook));
+        }
+
+        let mut source = if self.fanotify {
+            Source::Fanotify(Fanotify::new(&self.top_dir)
+                .with_context(|| format!("cannot fanotify the mount of \"{}\" - needs CAP_SYS_ADMIN", self.top_dir.display()))?)
+        } else {
+            let mut ino = Inotify::new().context("cannot start inotify")?;
+            match ino.watch_tree(&self.top_dir, &excludes) {
+                Ok(_) => Source::Inotify(ino),
+                Err(e) if is_watch_limit(&e) => {
+                    warn_watch_limit(self.rescan_every);
+                    Source::Rescan
+                }
+                Err(e) => return Err(e).with_context(|| format!("cannot watch \"{}\"", self.top_dir.display())),
+            }
+        };
+        info!("watching {} using {}", self.top_dir.display(), source);
+
+        let mut live = Live {
+            excludes,
+            accepted,
+            hash: self.hash,
+            buf: vec![0u8; self.buffer_size],
+            settle: self.settle,
+            stability: self.stability,
+            follow_symlinks: self.follow_symlinks,
+            state: self.state,
+            set,
+            events: Dispatcher::new(self.sinks, self.policy),
+            hooks: self.hooks,
+            rescan: self.rescan,
+            pending: HashMap::new(),
+            summary: ScanSummary::default(),
+            dirty: false,
+        };
+
+        let mut last_save = Instant::now();
+        let mut last_rescan = Instant::now();
+        while !stop.load(Ordering::Relaxed) {
+            let notices = match &mut source {
+                Source::Inotify(i) => i.read(POLL)?,
+                Source::Fanotify(f) => f.read(POLL)?,
+                Source::Rescan => {
+                    std::thread::sleep(POLL);
+                    vec![]
+                }
+            };
+            for n in notices {
+                live.notice(n, &mut source, &self.top_dir, self.rescan_every)?;
+            }
+            live.hash_settled();
+            if live.dirty && last_save.elapsed() >= self.save_every {
+                live.save()?;
+                last_save = Instant::now();
+            }
+            if !matches!(source, Source::Inotify(_)) && last_rescan.elapsed() >= self.rescan_every {
+                live.rescan()?;
+                last_rescan = Instant::now();
+            }
+        }
+
+        info!("stopping, {} files were still settling", live.pending.len());
+        if live.dirty {
+            live.save()?;
+        }
+        live.events.flush();
+        Ok(())
+    }
+}
+
+/// what the notify fd told us, in terms of paths
+#[derive(Debug)]
+enum Notice {
+    /// written, created, moved in or touched - rehash once settled
+    Changed(PathBuf),
+    /// a file or a whole directory is gone
+    Gone(PathBuf),
+    /// a new directory to watch
+    DirAdded(PathBuf),
+    Moved { from: PathBuf, to: PathBuf, is_dir: bool },
+    /// the kernel queue filled and events were dropped
+    Overflow,
+}
+
+enum Source {
+    Inotify(Inotify),
+    Fanotify(Fanotify),
+    /// watches ran out, only periodic rescans
+    Rescan,
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::Inotify(i) => write!(f, "inotify on {} directories", i.wds.len()),
+            Source::Fanotify(_) => f.write_str("fanotify on the mount"),
+            Source::Rescan => f.write_str("periodic rescans"),
+        }
+    }
+}
+
+fn is_watch_limit(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::ENOSPC)
+}
+
+fn warn_watch_limit(rescan_every: Duration) {
+    warn!("inotify watch limit reached, falling back to full scans every {} secs - raise fs.inotify.max_user_watches to avoid this",
+        rescan_every.as_secs());
+}
+
+/// where `old` ends up when `from` is renamed to `to`, none if it is not under `from`
+fn moved_path(old: &Path, from: &Path, to: &Path) -> Option<PathBuf> {
+    match old.strip_prefix(from) {
+        Ok(rel) if rel.as_os_str().is_empty() => Some(to.to_path_buf()),
+        Ok(rel) => Some(to.join(rel)),
+        Err(_) => None,
+    }
+}
+
+/// the watcher's state while running
+struct Live {
+    excludes: GlobSet,
+    /// corrupt files whose new hash is taken
+    accepted: GlobSet,
+    hash: HashAlgo,
+    buf: Vec<u8>,
+    settle: Duration,
+    stability: Stability,
+    follow_symlinks: bool,
+    state: Box<dyn StateBackend>,
+    set: ShaSet,
+    events: Dispatcher,
+    hooks: Hooks,
+    rescan: Option<Box<dyn FnMut() -> Result<ScanSummary>>>,
+    /// paths waiting to settle and when they were last seen changing
+    pending: HashMap<PathBuf, Instant>,
+    /// changes since the last save
+    summary: ScanSummary,
+    dirty: bool,
+}
+
+impl Live {
+    fn notice(&mut self, n: Notice, source: &mut Source, top_dir: &Path, rescan_every: Duration) -> Result<()> {
+        match n {
+            Notice::Overflow => {
+                warn!("too many changes at once and events were dropped - rescanning");
+                if let Source::Inotify(i) = source {
+                    if let Err(e) = i.watch_tree(top_dir, &self.excludes) {
+                        if is_watch_limit(&e) {
+                            warn_watch_limit(rescan_every);
+                            *source = Source::Rescan;
+                        }
+                    }
+                }
+                self.rescan()?;
+            }
+            Notice::Changed(p) => {
+                if !self.excludes.is_match(&p) {
+                    self.pending.insert(p, Instant::now());
+                }
+            }
+            Notice::Gone(p) => self.remove_under(&p),
+            Notice::DirAdded(p) => self.add_dir(&p, source, rescan_every),
+            Notice::Moved { from, to, is_dir } => {
+                for old in self.set.paths_under(&from) {
+                    let e = match self.set.remove(&old) {
+                        Some(e) => e,
+                        None => continue,
+                    };
+                    let new = match moved_path(&old, &from, &to) {
+                        Some(new) => new,
+                        None => continue,
+                    };
+                    self.dirty = true;
+                    if self.excludes.is_match(&new) {
+                        self.summary.deleted += 1;
+                        self.events.emit(&Event::Deleted { path: old, sha: e.sha().clone() });
+                        continue;
+                    }
+                    self.set.remove(&new);
+                    self.summary.moved += 1;
+                    self.events.emit(&Event::Moved { from: old, to: new.clone(), sha: e.sha().clone() });
+                    if let Err(e) = self.set.add(e.renamed(new)) {
+                        error!("cannot add moved entry: {:#}", e);
+                    }
+                }
+                // writes still settling follow the move
+                let settling: Vec<PathBuf> = self.pending.keys().filter(|k| k.starts_with(&from)).cloned().collect();
+                for old in settling {
+                    self.pending.remove(&old);
+                    if let Some(new) = moved_path(&old, &from, &to).filter(|n| !self.excludes.is_match(n)) {
+                        self.pending.insert(new, Instant::now());
+                    }
+                }
+                if is_dir {
+                    self.add_dir(&to, source, rescan_every);
+                } else if !self.excludes.is_match(&to) {
+                    // a temp file renamed over a file in the state replaces it, so always rehash
+                    self.pending.insert(to, Instant::now());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// watch a new or moved directory and hash anything in it not already in the state
+    fn add_dir(&mut self, dir: &Path, source: &mut Source, rescan_every: Duration) {
+        if self.excludes.is_match(dir) {
+            return;
+        }
+        if self.set.get(dir).is_none() {
+            self.pending.insert(dir.to_path_buf(), Instant::now());
+        }
+        if let Source::Inotify(i) = source {
+            match i.watch_tree(dir, &self.excludes) {
+                Ok(files) => {
+                    let now = Instant::now();
+                    let set = &self.set;
+                    self.pending.extend(files.into_iter().filter(|f| set.get(f).is_none()).map(|f| (f, now)));
+                }
+                Err(e) if is_watch_limit(&e) => {
+                    warn_watch_limit(rescan_every);
+                    *source = Source::Rescan;
+                }
+                Err(e) => debug!("cannot watch \"{}\": {}", dir.display(), e),
+            }
+        }
+    }
+
+    /// drop a path, or everything under it for a directory, from the state
+    fn remove_under(&mut self, p: &Path) {
+        self.pending.retain(|k, _| !k.starts_with(p));
+        for old in self.set.paths_under(p) {
+            if let Some(e) = self.set.remove(&old) {
+                self.dirty = true;
+                self.summary.deleted += 1;
+                self.events.emit(&Event::Deleted { path: old, sha: e.sha().clone() });
+            }
+        }
+    }
+
+    /// prior size of an append only file
+    fn mark(&self, p: &Path) -> Option<u64> {
+        self.set.get(p).and_then(|e| self.events.policy().mark(e, self.hash))
+    }
+
+    fn hash_settled(&mut self) {
+        let now = Instant::now();
+        let ready: Vec<PathBuf> = self.pending.iter()
+            .filter(|(_, t)| now.duration_since(**t) >= self.settle)
+            .map(|(p, _)| p.clone())
+            .collect();
+        for p in ready {
+            self.pending.remove(&p);
+            let mark = self.mark(&p);
+            let md = match fs::symlink_metadata(&p) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    self.remove_under(&p);
+                    continue;
+                }
+                // followed, a link is taken as what it points to when that is there
+                Ok(md) if self.follow_symlinks && md.file_type().is_symlink() => Ok(fs::metadata(&p).unwrap_or(md)),
+                md => md,
+            };
+            match md {
+                Ok(md) if !md.is_file() => {
+                    let target = if md.file_type().is_symlink() { fs::read_link(&p).ok() } else { None };
+                    self.record(ShaState::special(p, &md, target));
+                }
+                // still changing after the retries, the file waits to settle again
+                _ => match sha_a_file(&p, &mut self.buf, self.hash, mark, self.stability, None) {
+                    Err(e) => {
+                        self.dirty |= self.summary.record_error(&mut self.set, &p, e.kind);
+                        self.events.emit(&e.event(p));
+                    }
+                    Ok(Hashed::Unstable(why)) => {
+                        debug!("\"{}\" {}, waiting for it to settle again", p.display(), why);
+                        self.pending.insert(p, Instant::now());
+                    }
+                    Ok(Hashed::Done(new, size)) => {
+                        self.summary.files += 1;
+                        self.summary.bytes += size;
+                        self.record(*new);
+                    }
+                },
+            }
+        }
+    }
+
+    /// put a new or changed entry in the state and send what the policy makes of it
+    fn record(&mut self, new: ShaState) {
+        match self.set.add_with_prior(new.clone()) {
+            Err(e) => error!("Cannot add entry for {} due to {}", new, e),
+            Ok((diff, old)) => {
+                let (diff, old) = match old {
+                    Some(o) if o.is_unreadable() => (DiffResult::Added, None),
+                    o => (diff, o),
+                };
+                let evs = match &old {
+                    Some(old) => {
+                        let (evs, keep_old) = confirm_rot(self.events.policy().changes(old, &new), old, &new, &self.accepted);
+                        if keep_old {
+                            let _ = self.set.add_with_prior(old.clone());
+                        }
+                        evs
+                    }
+                    None => vec![Event::Added { path: new.path().to_path_buf(), sha: new.sha().clone(), size: new.size().unwrap_or(0) }],
+                };
+                if let Some(Event::Corruption { path, .. }) = evs.first() {
+                    self.summary.corrupted.push(path.clone());
+                }
+                let kind = if diff == DiffResult::Added { diff } else { change_kind(&evs) };
+                self.summary.count(kind);
+                if diff != DiffResult::Same || kind != DiffResult::Same {
+                    self.dirty = true;
+                }
+                for ev in evs {
+                    self.events.emit(&ev);
+                }
+            }
+        }
+    }
+
+    /// save the state, then run the on complete hook with what changed since the last save
+    fn save(&mut self) -> Result<()> {
+        self.state.save(&self.set)?;
+        self.dirty = false;
+        let mut summary = std::mem::take(&mut self.summary);
+        summary.alerts = std::mem::take(&mut self.events.alerts);
+        info!("saved state {}: {} added, {} changed, {} deleted, {} moved, {} errors", self.state.describe(),
+            summary.added, summary.changed(), summary.deleted, summary.moved, summary.errors);
+        if let Err(e) = run_on_complete(&self.hooks, &serde_json::to_string(&summary)?) {
+            error!("{:#}", e);
+        }
+        Ok(())
+    }
+
+    fn rescan(&mut self) -> Result<()> {
+        let rescan = match &mut self.rescan {
+            Some(f) => f,
+            None => {
+                warn!("no rescan set up, changes may have been missed");
+                return Ok(());
+            }
+        };
+        self.state.save(&self.set)?;
+        info!("rescanning");
+        self.state.unlock();
+        let r = rescan();
+        self.state.lock()?;
+        let s = r?;
+        info!("rescan of {} files found {} added, {} changed, {} deleted, {} moved, {} errors",
+            s.files, s.added, s.changed(), s.deleted, s.moved, s.errors);
+        self.set = self.state.load()?;
+        Ok(())
+    }
+}
+
+/// wait up to `timeout` for `fd` to be readable
+fn wait_readable(fd: RawFd, timeout: Duration) -> io::Result<bool> {
+    let mut p = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+    let r = unsafe { libc::poll(&mut p, 1, timeout.as_millis() as libc::c_int) };
+    if r < 0 {
+        let e = io::Error::last_os_error();
+        return match e.kind() {
+            io::ErrorKind::Interrupted => Ok(false),
+            _ => Err(e),
+        };
+    }
+    Ok(r > 0)
+}
+
+/// read what is there from a non blocking fd, None once it would block
+fn read_some(fd: RawFd, buf: &mut [u8]) -> io::Result<Option<usize>> {
+    let n = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+    if n < 0 {
+        let e = io::Error::last_os_error();
+        return match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => Ok(None),
+            _ => Err(e),
+        };
+    }
+    Ok(Some(n as usize))
+}
+
+///
+/// A watch on every directory of the tree
+///
+/// Moves within the tree come as a MOVED_FROM and MOVED_TO pair sharing a cookie.  The
+/// MOVED_FROM is held for `MOVE_WAIT` and taken as a delete if no pair shows up.
+struct Inotify {
+    fd: RawFd,
+    wds: HashMap<libc::c_int, PathBuf>,
+    moves: HashMap<u32, (PathBuf, Instant)>,
+}
+
+impl Inotify {
+    fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Inotify { fd, wds: HashMap::new(), moves: HashMap::new() })
+    }
+
+    fn watch(&mut self, dir: &Path) -> io::Result<()> {
+        let c = CString::new(dir.as_os_str().as_bytes())?;
+        let wd = unsafe { libc::inotify_add_watch(self.fd, c.as_ptr(), WATCH_MASK) };
+        if wd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // an existing watch keeps its wd, so this also follows a moved directory
+        self.wds.insert(wd, dir.to_path_buf());
+        Ok(())
+    }
+
+    /// watch `dir` and every directory under it, returns the files found along the way
+    fn watch_tree(&mut self, dir: &Path, excludes: &GlobSet) -> io::Result<Vec<PathBuf>> {
+        let mut files = vec![];
+        let mut dirs = vec![dir.to_path_buf()];
+        while let Some(d) = dirs.pop() {
+            match self.watch(&d) {
+                Err(e) if is_watch_limit(&e) => return Err(e),
+                Err(e) => {
+                    debug!("cannot watch \"{}\": {}", d.display(), e);
+                    continue;
+                }
+                Ok(()) => (),
+            }
+            let rd = match fs::read_dir(&d) {
+                Ok(rd) => rd,
+                Err(e) => {
+                    debug!("cannot list \"{}\": {}", d.display(), e);
+                    continue;
+                }
+            };
+            for e in rd.flatten() {
+                let p = e.path();
+                if excludes.is_match(&p) {
+                    continue;
+                }
+                match e.file_type() {
+                    Ok(ft) if ft.is_dir() => dirs.push(p),
+                    Ok(ft) if ft.is_file() => files.push(p),
+                    _ => (),
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    fn read(&mut self, timeout: Duration) -> io::Result<Vec<Notice>> {
+        let mut out = vec![];
+        if wait_readable(self.fd, timeout)? {
+            let mut buf = vec![0u8; 64 * 1024];
+            while let Some(n) = read_some(self.fd, &mut buf)? {
+                let mut off = 0;
+                while off + size_of::<libc::inotify_event>() <= n {
+                    let ev: libc::inotify_event = unsafe { std::ptr::read_unaligned(buf[off..].as_ptr() as *const _) };
+                    let start = off + size_of::<libc::inotify_event>();
+                    let name = &buf[start..start + ev.len as usize];
+                    let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+                    self.decode(&ev, OsStr::from_bytes(name), &mut out);
+                    off = start + ev.len as usize;
+                }
+            }
+        }
+        let now = Instant::now();
+        let expired: Vec<u32> = self.moves.iter()
+            .filter(|(_, (_, t))| now.duration_since(*t) >= MOVE_WAIT)
+            .map(|(c, _)| *c)
+            .collect();
+        for c in expired {
+            if let Some((p, _)) = self.moves.remove(&c) {
+                out.push(Notice::Gone(p));
+            }
+        }
+        Ok(out)
+    }
+
+    fn decode(&mut self, ev: &libc::inotify_event, name: &OsStr, out: &mut Vec<Notice>) {
+        if ev.mask & libc::IN_Q_OVERFLOW != 0 {
+            out.push(Notice::Overflow);
+            return;
+        }
+        if ev.mask & libc::IN_IGNORED != 0 {
+            self.wds.remove(&ev.wd);
+            return;
+        }
+        // events on a watched directory itself are also seen from its parent
+        let path = match self.wds.get(&ev.wd) {
+            Some(dir) if !name.is_empty() => dir.join(name),
+            _ => return,
+        };
+        let is_dir = ev.mask & libc::IN_ISDIR != 0;
+        if ev.mask & libc::IN_MOVED_FROM != 0 {
+            self.moves.insert(ev.cookie, (path, Instant::now()));
+        } else if ev.mask & libc::IN_MOVED_TO != 0 {
+            out.push(match self.moves.remove(&ev.cookie) {
+                Some((from, _)) => Notice::Moved { from, to: path, is_dir },
+                None if is_dir => Notice::DirAdded(path),
+                None => Notice::Changed(path),
+            });
+        } else if ev.mask & libc::IN_DELETE != 0 {
+            out.push(Notice::Gone(path));
+        } else if is_dir {
+            if ev.mask & libc::IN_CREATE != 0 {
+                out.push(Notice::DirAdded(path));
+            }
+        } else {
+            out.push(Notice::Changed(path));
+        }
+    }
+}
+
+impl Drop for Inotify {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+///
+/// Writes anywhere on the mount holding the tree
+///
+/// Only CLOSE_WRITE is asked for, which covers content changes.  The written file's path is
+/// read back from the fd the event carries, and writes outside the tree are dropped.
+struct Fanotify {
+    fd: RawFd,
+    /// the tree as given, which is how the state names paths
+    top_dir: PathBuf,
+    real_top: PathBuf,
+}
+
+impl Fanotify {
+    fn new(top_dir: &Path) -> io::Result<Self> {
+        let fd = unsafe {
+            libc::fanotify_init(libc::FAN_CLASS_NOTIF | libc::FAN_CLOEXEC | libc::FAN_NONBLOCK,
+                                (libc::O_RDONLY | libc::O_LARGEFILE | libc::O_CLOEXEC) as libc::c_uint)
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let f = Fanotify { fd, top_dir: top_dir.to_path_buf(), real_top: top_dir.canonicalize()? };
+        let c = CString::new(f.real_top.as_os_str().as_bytes())?;
+        let r = unsafe {
+            libc::fanotify_mark(fd, libc::FAN_MARK_ADD | libc::FAN_MARK_MOUNT, libc::FAN_CLOSE_WRITE, libc::AT_FDCWD, c.as_ptr())
+        };
+        if r < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(f)
+    }
+
+    fn read(&mut self, timeout: Duration) -> io::Result<Vec<Notice>> {
+        let mut out = vec![];
+        if !wait_readable(self.fd, timeout)? {
+            return Ok(out);
+        }
+        let mut buf = vec![0u8; 64 * 1024];
+        let meta_len = size_of::<libc::fanotify_event_metadata>();
+        while let Some(n) = read_some(self.fd, &mut buf)? {
+            let mut off = 0;
+            while off + meta_len <= n {
+                let m: libc::fanotify_event_metadata = unsafe { std::ptr::read_unaligned(buf[off..].as_ptr() as *const _) };
+                if m.vers != libc::FANOTIFY_METADATA_VERSION || (m.event_len as usize) < meta_len {
+                    return Err(io::Error::other(format!("unexpected fanotify metadata version {}", m.vers)));
+                }
+                off += m.event_len as usize;
+                if m.mask & libc::FAN_Q_OVERFLOW != 0 {
+                    out.push(Notice::Overflow);
+                }
+                if m.fd == libc::FAN_NOFD {
+                    continue;
+                }
+                let path = fs::read_link(format!("/proc/self/fd/{}", m.fd));
+                unsafe { libc::close(m.fd) };
+                match path {
+                    Ok(p) => if let Ok(rel) = p.strip_prefix(&self.real_top) {
+                        out.push(Notice::Changed(self.top_dir.join(rel)));
+                    },
+                    Err(e) => debug!("cannot find path of fanotify event: {}", e),
+                }
+            }
+        }
+        Ok(out)
+    }
+}
+
+impl Drop for Fanotify {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}